@@ -1,8 +1,13 @@
 use std::sync::LazyLock;
 
-use axum::{Router, extract::State, response::Html, routing::get};
-use redis;
-use redis_rate::Limiter;
+use axum::{
+    Router,
+    extract::State,
+    http::StatusCode,
+    response::{AppendHeaders, Html, IntoResponse, Response},
+    routing::get,
+};
+use redis_rate::{Limiter, RateLimitError};
 
 static KNOCK_LIMIT: LazyLock<redis_rate::Limit> =
     LazyLock::new(|| redis_rate::new_limit!(1, 1, 10));
@@ -17,9 +22,11 @@ async fn main() {
     #[cfg(feature = "local_accelerate")]
     {
         let limiter_clone = limiter.clone();
-        tokio::spawn(async move {
-            while let Err(e) = limiter_clone.start_event_sync() {
-                eprintln!("Error: {}", e);
+        // The sync blocks, so it runs on its own thread instead of a Tokio worker.
+        // It only returns once the sync stops for good, retrying would fail the same way.
+        std::thread::spawn(move || {
+            if let Err(e) = limiter_clone.start_event_sync() {
+                eprintln!("event sync stopped: {}", e);
             }
         });
     }
@@ -38,26 +45,37 @@ async fn main() {
     axum::serve(listener, app).await.unwrap();
 }
 
-async fn knock(State(limiter): State<Limiter>) -> Html<&'static str> {
-    let result = limiter.allow(KNOCK_LIMIT_KEY, &KNOCK_LIMIT);
-    match result {
+async fn knock(State(limiter): State<Limiter>) -> Response {
+    match limiter.allow_or_error(KNOCK_LIMIT_KEY, &KNOCK_LIMIT) {
         Ok(result) => {
-            if !result.limited {
-                let response = format!(
-                    "<h1>Who's there? Remaining {} requests.</h1>",
-                    result.remaining
-                );
-                println!("Effective knock request");
-                Html(Box::leak(response.into_boxed_str()))
-            } else {
-                let response = format!(
-                    "<h1>Too many requests. Try again in {} seconds.</h1>",
-                    result.retry_after.unwrap_or_default().as_secs()
-                );
-                Html(Box::leak(response.into_boxed_str()))
-            }
+            println!("Effective knock request");
+            Html(format!(
+                "<h1>Who's there? Remaining {} requests.</h1>",
+                result.remaining
+            ))
+            .into_response()
+        }
+        Err(RateLimitError::Limited(result)) => {
+            let (status, headers) = result.to_http_parts();
+            let body = Html(format!(
+                "<h1>Too many requests. Try again in {} seconds.</h1>",
+                result.retry_after.unwrap_or_default().as_secs()
+            ));
+            let status = StatusCode::from_u16(status).unwrap();
+            (status, AppendHeaders(headers), body).into_response()
         }
-        Err(_) => Html("<h1>Internal server error</h1>"),
+        // Redis can't be reached, the client can retry shortly.
+        Err(e) if e.is_unavailable() => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [("Retry-After", "1")],
+            Html("<h1>Service unavailable</h1>"),
+        )
+            .into_response(),
+        Err(_) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Html("<h1>Internal server error</h1>"),
+        )
+            .into_response(),
     }
 }
 
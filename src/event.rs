@@ -0,0 +1,130 @@
+use std::fmt::Write;
+
+/// Version tag of the event payload format.
+/// Subscribers ignore the payloads with other tags,
+/// so new formats can be introduced without breaking older instances.
+const EVENT_SCHEMA_TAG: &str = "v1:";
+
+const RESET_EVENT_KIND: &str = "reset";
+const CONSUME_EVENT_KIND: &str = "consume";
+
+/// Event published to the limiter's event channel.
+///
+/// After the schema tag, the payload is a sequence of length-prefixed fields
+/// in the form of `{byte length}:{field}`, starting with the kind of the event.
+/// So keys can contain any characters without being mis-parsed,
+/// and fields appended in the future are ignored by older subscribers.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Event<'a> {
+    /// The limit of the key is reset.
+    Reset { key: &'a str },
+    /// Requests of the key are consumed and the limit resets after the duration.
+    Consume { key: &'a str, reset_after_secs: f64 },
+}
+
+impl<'a> Event<'a> {
+    /// Encode the event into a pubsub payload.
+    pub(crate) fn encode(&self) -> String {
+        let mut payload = EVENT_SCHEMA_TAG.to_string();
+        match self {
+            Event::Reset { key } => {
+                push_field(&mut payload, RESET_EVENT_KIND);
+                push_field(&mut payload, key);
+            }
+            Event::Consume {
+                key,
+                reset_after_secs,
+            } => {
+                push_field(&mut payload, CONSUME_EVENT_KIND);
+                push_field(&mut payload, key);
+                push_field(&mut payload, &reset_after_secs.to_string());
+            }
+        }
+        payload
+    }
+
+    /// Decode an event from a pubsub payload.
+    /// Returns `None` if the payload is malformed or not understood,
+    /// including a duration which is negative or not finite.
+    pub(crate) fn decode(payload: &'a str) -> Option<Self> {
+        let mut fields = payload.strip_prefix(EVENT_SCHEMA_TAG)?;
+        let kind = next_field(&mut fields)?;
+        let key = next_field(&mut fields)?;
+        match kind {
+            RESET_EVENT_KIND => Some(Event::Reset { key }),
+            CONSUME_EVENT_KIND => {
+                let reset_after_secs: f64 = next_field(&mut fields)?.parse().ok()?;
+                if !reset_after_secs.is_finite() || reset_after_secs < 0.0 {
+                    return None;
+                }
+                Some(Event::Consume {
+                    key,
+                    reset_after_secs,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+fn push_field(payload: &mut String, field: &str) {
+    // Writing to a `String` never fails.
+    let _ = write!(payload, "{}:{}", field.len(), field);
+}
+
+fn next_field<'a>(fields: &mut &'a str) -> Option<&'a str> {
+    let (len, rest) = fields.split_once(':')?;
+    let len: usize = len.parse().ok()?;
+    let field = rest.get(..len)?;
+    *fields = rest.get(len..)?;
+    Some(field)
+}
+
+#[test]
+fn test_event_round_trip() {
+    let event = Event::Reset {
+        key: "redis_rate:reset:5:a",
+    };
+    assert_eq!(Event::decode(&event.encode()), Some(event));
+
+    let event = Event::Consume {
+        key: "redis_rate:a:b",
+        reset_after_secs: 1.5,
+    };
+    assert_eq!(Event::decode(&event.encode()), Some(event));
+}
+
+#[test]
+fn test_event_unknown_payload() {
+    assert_eq!(Event::decode("reset:redis_rate:a"), None);
+    assert_eq!(Event::decode("v2:5:reset12:redis_rate:a"), None);
+    assert_eq!(Event::decode("v1:7:unknown12:redis_rate:a"), None);
+    assert_eq!(Event::decode("v1:5:reset99:redis_rate:a"), None);
+    assert_eq!(Event::decode("v1:5:resetx:redis_rate:a"), None);
+    assert_eq!(Event::decode("v1:7:consume12:redis_rate:a4:nan?"), None);
+}
+
+#[test]
+fn test_event_extra_fields() {
+    assert_eq!(
+        Event::decode("v1:5:reset12:redis_rate:a3:new"),
+        Some(Event::Reset {
+            key: "redis_rate:a"
+        })
+    );
+}
+
+#[test]
+fn test_event_invalid_duration() {
+    assert_eq!(Event::decode("v1:7:consume1:a3:NaN"), None);
+    assert_eq!(Event::decode("v1:7:consume1:a2:-1"), None);
+    assert_eq!(Event::decode("v1:7:consume1:a3:inf"), None);
+    assert_eq!(Event::decode("v1:7:consume1:a4:-inf"), None);
+    assert_eq!(
+        Event::decode("v1:7:consume1:a1:0"),
+        Some(Event::Consume {
+            key: "a",
+            reset_after_secs: 0.0
+        })
+    );
+}
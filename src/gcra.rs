@@ -0,0 +1,79 @@
+/// Make a GCRA decision, with the same math as the Redis script.
+///
+/// All the times are in seconds, `tat` is the stored theoretical arrival time,
+/// which should be `now` if nothing is stored for the key.
+///
+/// A `tat` more than `burst_offset` after `now`, left by a clock which moved backward,
+/// is clamped to `now + burst_offset`.
+///
+/// Returns `(limited, remaining, retry_after, reset_after)`,
+/// where `retry_after` is `-1.0` if the request is not limited.
+/// When the request is not limited, the new tat is `now + reset_after`.
+pub fn gcra_decide(
+    now: f64,
+    tat: f64,
+    emission_interval: f64,
+    burst_offset: f64,
+    tat_increment: f64,
+) -> (bool, usize, f64, f64) {
+    // A tat beyond a full burst was stored before the clock moved backward,
+    // clamp it so the key isn't limited for as long as the clock jump.
+    let tat = tat.min(now + burst_offset);
+    let new_tat = tat.max(now) + tat_increment;
+    let allow_at = new_tat - burst_offset;
+
+    if allow_at > now {
+        let remaining = ((now - tat.max(now) + burst_offset) / emission_interval).floor();
+        (true, remaining as usize, allow_at - now, tat.max(now) - now)
+    } else {
+        let remaining = ((now - allow_at) / emission_interval).floor();
+        (false, remaining as usize, -1.0, new_tat - now)
+    }
+}
+
+#[test]
+fn test_gcra_decide() {
+    // 5 requests per 20 seconds, burst 5.
+    let (emission_interval, burst_offset) = (4.0, 20.0);
+    let now = 100.0;
+
+    let (limited, remaining, retry_after, reset_after) =
+        gcra_decide(now, now, emission_interval, burst_offset, 16.0);
+    assert!(!limited);
+    assert_eq!(remaining, 1);
+    assert_eq!(retry_after, -1.0);
+    assert_eq!(reset_after, 16.0);
+
+    let tat = now + reset_after;
+    let (limited, remaining, retry_after, reset_after) =
+        gcra_decide(now, tat, emission_interval, burst_offset, 12.0);
+    assert!(limited);
+    assert_eq!(remaining, 1);
+    assert_eq!(retry_after, 8.0);
+    assert_eq!(reset_after, 16.0);
+
+    let (limited, remaining, _, reset_after) =
+        gcra_decide(now, tat, emission_interval, burst_offset, 4.0);
+    assert!(!limited);
+    assert_eq!(remaining, 0);
+    assert_eq!(reset_after, 20.0);
+}
+
+#[test]
+fn test_gcra_decide_future_tat() {
+    // The clock moved 1000 seconds backward since the tat was stored.
+    let (limited, remaining, retry_after, reset_after) = gcra_decide(100.0, 1100.0, 4.0, 20.0, 4.0);
+    assert!(limited);
+    assert_eq!(remaining, 0);
+    assert_eq!(retry_after, 4.0);
+    assert_eq!(reset_after, 20.0);
+}
+
+#[test]
+fn test_gcra_decide_stale_tat() {
+    let (limited, remaining, retry_after, reset_after) = gcra_decide(100.0, 0.0, 4.0, 20.0, 24.0);
+    assert!(limited);
+    assert_eq!(remaining, 5);
+    assert_eq!(retry_after, 4.0);
+    assert_eq!(reset_after, 0.0);
+}
@@ -0,0 +1,122 @@
+use std::{error, fmt};
+
+use crate::Limit;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Error of converting a `governor::Quota` into a `Limit`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaConversionError {
+    quota: governor::Quota,
+}
+
+impl fmt::Display for QuotaConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "quota replenishing 1 cell per {:?} with burst {} can't be represented as a limit",
+            self.quota.replenish_interval(),
+            self.quota.burst_size()
+        )
+    }
+}
+
+impl error::Error for QuotaConversionError {}
+
+/// Convert a `governor::Quota` into a `Limit` with the same GCRA parameters, or close to them.
+///
+/// `governor` replenishes one cell per `replenish_interval`,
+/// which is the emission interval `period_seconds / rate` of a `Limit`.
+/// If a whole number of cells, at most `burst`, are replenished in a whole number of seconds,
+/// `period_seconds` is the smallest such number of seconds and `rate` is that number of cells,
+/// so the conversion is exact.
+/// For example, `Quota::per_second(10)` becomes `rate: 10, period_seconds: 1`,
+/// and `Quota::per_minute(5)` becomes `rate: 1, period_seconds: 12`.
+/// `burst` is the `burst_size` of the quota.
+///
+/// Otherwise, e.g. for `Quota::per_second(3)` whose interval is truncated to 333333333ns,
+/// `period_seconds` is the time to replenish the whole burst rounded up to whole seconds,
+/// and `rate` is the number of cells replenished in it rounded to the nearest.
+/// So `Quota::per_second(3)` becomes `rate: 3, period_seconds: 1`,
+/// and the emission interval is off by less than `1 / (2 * rate)` of itself.
+///
+/// The conversion fails if even the approximation needs `rate` greater than `burst`,
+/// i.e. cells are replenished faster than `burst` per second,
+/// which `governor` allows but `Limit` doesn't.
+impl TryFrom<governor::Quota> for Limit {
+    type Error = QuotaConversionError;
+
+    fn try_from(quota: governor::Quota) -> Result<Self, Self::Error> {
+        let err = QuotaConversionError { quota };
+        let interval_nanos = quota.replenish_interval().as_nanos();
+        if interval_nanos == 0 {
+            return Err(err);
+        }
+
+        let burst = quota.burst_size().get() as usize;
+
+        let gcd = gcd(interval_nanos, NANOS_PER_SEC);
+        let (mut period_seconds, mut rate) = (interval_nanos / gcd, NANOS_PER_SEC / gcd);
+        if rate > burst as u128 {
+            period_seconds = (burst as u128 * interval_nanos).div_ceil(NANOS_PER_SEC);
+            rate = (period_seconds * NANOS_PER_SEC + interval_nanos / 2) / interval_nanos;
+        }
+        let period_seconds = usize::try_from(period_seconds).map_err(|_| err.clone())?;
+        let rate = usize::try_from(rate).map_err(|_| err.clone())?;
+        if rate == 0 || rate > burst {
+            return Err(err);
+        }
+
+        Ok(Limit::new(rate, burst, period_seconds))
+    }
+}
+
+fn gcd(mut a: u128, mut b: u128) -> u128 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+#[test]
+fn test_limit_from_quota() {
+    use std::{num::NonZeroU32, time::Duration};
+
+    let limit = Limit::try_from(governor::Quota::per_second(NonZeroU32::new(10).unwrap())).unwrap();
+    assert_eq!(limit, Limit::new(10, 10, 1));
+
+    let limit = Limit::try_from(governor::Quota::per_minute(NonZeroU32::new(5).unwrap())).unwrap();
+    assert_eq!(limit, Limit::new(1, 5, 12));
+
+    let quota = governor::Quota::with_period(Duration::from_millis(1500))
+        .unwrap()
+        .allow_burst(NonZeroU32::new(3).unwrap());
+    let limit = Limit::try_from(quota).unwrap();
+    assert_eq!(limit, Limit::new(2, 3, 3));
+
+    // Intervals which aren't a whole number of nanoseconds are approximated.
+    for (quota, expected) in [
+        (
+            governor::Quota::per_second(NonZeroU32::new(3).unwrap()),
+            Limit::new(3, 3, 1),
+        ),
+        (
+            governor::Quota::per_second(NonZeroU32::new(7).unwrap()),
+            Limit::new(7, 7, 1),
+        ),
+        (
+            governor::Quota::per_minute(NonZeroU32::new(7).unwrap()),
+            Limit::new(7, 7, 60),
+        ),
+    ] {
+        let limit = Limit::try_from(quota).unwrap();
+        assert_eq!(limit, expected);
+        let interval = quota.replenish_interval().as_secs_f64();
+        assert!((limit.emission_interval().as_secs_f64() - interval).abs() < interval * 1e-6);
+    }
+
+    let quota = governor::Quota::with_period(Duration::from_millis(100))
+        .unwrap()
+        .allow_burst(NonZeroU32::new(1).unwrap());
+    assert!(Limit::try_from(quota).is_err());
+}
@@ -0,0 +1,126 @@
+use std::sync::Arc;
+
+use crate::{Limit, LimitResult, Limiter, RateLimitError};
+
+type KeyFn<C> = Arc<dyn Fn(&C) -> String + Send + Sync>;
+
+/// Several limiters checked together, e.g. a per-user and a per-endpoint limit,
+/// each with its own limit and a function making its key from the request context.
+///
+/// A request is allowed only if it is allowed by all the rules.
+/// If a rule limits the request, the requests already consumed by the previous rules
+/// are refunded, so a rejected request doesn't count against any of them.
+pub struct LimiterGroup<C> {
+    rules: Vec<(Limiter, Limit, KeyFn<C>)>,
+}
+
+impl<C> Default for LimiterGroup<C> {
+    fn default() -> Self {
+        Self { rules: Vec::new() }
+    }
+}
+
+impl<C> Clone for LimiterGroup<C> {
+    fn clone(&self) -> Self {
+        Self {
+            rules: self.rules.clone(),
+        }
+    }
+}
+
+impl<C> LimiterGroup<C> {
+    /// Create an empty group, which allows every request.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a rule checking the key made by `key_fn` against the limit with the limiter.
+    /// The rules are checked in the order they are added.
+    #[must_use]
+    pub fn add_rule(
+        mut self,
+        limiter: Limiter,
+        limit: Limit,
+        key_fn: impl Fn(&C) -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.rules.push((limiter, limit, Arc::new(key_fn)));
+        self
+    }
+
+    /// Allow a request to be made within all the rules.
+    /// Returns the result of the rule which limited the request,
+    /// or the one with the fewest remaining requests, `None` if the group has no rules.
+    #[must_use = "a rate-limit decision was computed but not acted on"]
+    pub fn allow(&self, ctx: &C) -> Result<Option<LimitResult>, RateLimitError> {
+        self.allow_n(ctx, 1)
+    }
+
+    /// Allow n requests to be made within all the rules, see `allow`.
+    ///
+    /// The refunds of the previous rules are best effort,
+    /// a failing refund doesn't hide the result or the error of the rule which stopped the check.
+    #[must_use = "a rate-limit decision was computed but not acted on"]
+    pub fn allow_n(&self, ctx: &C, n: usize) -> Result<Option<LimitResult>, RateLimitError> {
+        let mut consumed = Vec::with_capacity(self.rules.len());
+        let mut most_restrictive: Option<LimitResult> = None;
+        for (limiter, limit, key_fn) in &self.rules {
+            let key = key_fn(ctx);
+            let result = match limiter.allow_n(&key, limit, n) {
+                Ok(result) => result,
+                Err(e) => {
+                    Self::refund(consumed, n);
+                    return Err(e);
+                }
+            };
+            if result.limited {
+                Self::refund(consumed, n);
+                return Ok(Some(result));
+            }
+            consumed.push((limiter, key, limit));
+            if most_restrictive
+                .as_ref()
+                .is_none_or(|r| result.remaining < r.remaining)
+            {
+                most_restrictive = Some(result);
+            }
+        }
+        Ok(most_restrictive)
+    }
+
+    fn refund(consumed: Vec<(&Limiter, String, &Limit)>, n: usize) {
+        for (limiter, key, limit) in consumed {
+            let _ = limiter.rollback_n(&key, limit, n);
+        }
+    }
+}
+
+#[test]
+fn test_limiter_group() {
+    let users = Limiter::in_memory();
+    let endpoints = Limiter::in_memory();
+    let group = LimiterGroup::new()
+        .add_rule(
+            users.clone(),
+            Limit::new(1, 3, 10),
+            |(user, _): &(&str, &str)| user.to_string(),
+        )
+        .add_rule(endpoints.clone(), Limit::new(1, 2, 10), |(_, endpoint)| {
+            endpoint.to_string()
+        });
+
+    let result = group.allow(&("alice", "/a")).unwrap().unwrap();
+    assert!(!result.limited);
+    assert_eq!(result.remaining, 1);
+    assert!(!group.allow(&("alice", "/a")).unwrap().unwrap().limited);
+
+    // The endpoint limits the request, so the user gets its request back.
+    let result = group.allow(&("alice", "/a")).unwrap().unwrap();
+    assert!(result.limited);
+    assert_eq!(users.headroom("alice", &Limit::new(1, 3, 10)).unwrap(), 1);
+
+    let result = group.allow(&("alice", "/b")).unwrap().unwrap();
+    assert!(!result.limited);
+    assert_eq!(result.remaining, 0);
+
+    assert!(LimiterGroup::<()>::new().allow(&()).unwrap().is_none());
+}
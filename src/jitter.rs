@@ -0,0 +1,70 @@
+use std::{
+    cell::Cell,
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    sync::Arc,
+};
+
+/// Source of random numbers in `[0, 1)` for the retry jitter.
+#[derive(Clone)]
+pub(crate) struct JitterRng(Arc<dyn Fn() -> f64 + Send + Sync>);
+
+impl JitterRng {
+    pub(crate) fn new(rng: impl Fn() -> f64 + Send + Sync + 'static) -> Self {
+        JitterRng(Arc::new(rng))
+    }
+
+    /// Draw a number in `[0, 1)`, clamping what a custom source returns.
+    pub(crate) fn sample(&self) -> f64 {
+        let x = (self.0)();
+        if x.is_nan() {
+            0.0
+        } else {
+            x.clamp(0.0, 1.0 - f64::EPSILON)
+        }
+    }
+}
+
+impl Default for JitterRng {
+    fn default() -> Self {
+        JitterRng::new(thread_rng)
+    }
+}
+
+impl fmt::Debug for JitterRng {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("JitterRng")
+    }
+}
+
+thread_local! {
+    static XORSHIFT_STATE: Cell<u64> = Cell::new({
+        // Seeded from the random keys std uses for `HashMap`, so no dependency is needed.
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(0);
+        hasher.finish() | 1
+    });
+}
+
+/// Fast thread-local xorshift64, good enough to spread retries, not for cryptography.
+fn thread_rng() -> f64 {
+    XORSHIFT_STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        // The top 53 bits make a uniform double in [0, 1).
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    })
+}
+
+#[test]
+fn test_thread_rng() {
+    let samples: Vec<f64> = (0..1000).map(|_| JitterRng::default().sample()).collect();
+    assert!(samples.iter().all(|x| (0.0..1.0).contains(x)));
+    assert!(samples.iter().any(|x| *x < 0.5) && samples.iter().any(|x| *x >= 0.5));
+    assert_eq!(JitterRng::new(|| f64::NAN).sample(), 0.0);
+    assert!(JitterRng::new(|| 1.0).sample() < 1.0);
+}
@@ -1,6 +1,37 @@
+// Only the in-memory backend is built on wasm32, without the Redis client,
+// so the helpers of the Redis backend are unused there.
+#![cfg_attr(target_arch = "wasm32", allow(dead_code))]
+
+// The local cache relies on `Instant` and a blocking pubsub thread,
+// and the functions are loaded with the Redis client, none of which are available on wasm32.
+#[cfg(all(feature = "local_accelerate", target_arch = "wasm32"))]
+compile_error!("the `local_accelerate` feature is not supported on wasm32");
+#[cfg(all(feature = "functions", target_arch = "wasm32"))]
+compile_error!("the `functions` feature is not supported on wasm32");
+
+#[cfg(feature = "local_accelerate")]
+mod event;
+mod gcra;
+#[cfg(feature = "governor-compat")]
+mod governor_compat;
+mod group;
+mod jitter;
+mod memory;
+mod rate_limiter;
+#[cfg(not(target_arch = "wasm32"))]
+mod scan;
 mod scripts;
+mod stats;
 
-use std::time;
+use std::{
+    borrow::Cow,
+    error, fmt,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time,
+};
 
 #[cfg(feature = "local_accelerate")]
 use std::{
@@ -8,21 +39,103 @@ use std::{
     sync::{LazyLock, RwLock},
 };
 
-use scripts::ALLOW_N_SCRIPT;
+#[cfg(feature = "local_accelerate")]
+use event::Event;
+pub use gcra::gcra_decide;
+#[cfg(feature = "governor-compat")]
+pub use governor_compat::QuotaConversionError;
+pub use group::LimiterGroup;
+use jitter::JitterRng;
+use memory::MemoryStore;
+#[cfg(feature = "async")]
+pub use rate_limiter::AsyncRateLimiter;
+pub use rate_limiter::{NoopRateLimiter, RateLimiter};
+#[cfg(not(target_arch = "wasm32"))]
+use redis::Commands;
+#[cfg(not(target_arch = "wasm32"))]
+pub use scan::{ExportIter, KeysIter};
+#[cfg(not(target_arch = "wasm32"))]
+use scripts::{
+    ALLOW_N_FUNCTION, ALLOW_N_SCRIPT, FIXED_WINDOW_REFUND_SCRIPT, FIXED_WINDOW_SCRIPT,
+    PEEK_N_FUNCTION, PEEK_N_SCRIPT, REFUND_N_FUNCTION, REFUND_N_SCRIPT,
+};
+pub use scripts::{
+    ALLOW_N_SOURCE, FIXED_WINDOW_REFUND_SOURCE, FIXED_WINDOW_SOURCE, PEEK_N_SOURCE, REFUND_N_SOURCE,
+};
+#[cfg(feature = "functions")]
+use scripts::{FUNCTION_LIBRARY, FUNCTION_LIBRARY_NAME};
+pub use stats::LimiterStats;
+use stats::StatsCounters;
 
 #[cfg(feature = "local_accelerate")]
-static RESET_TIME_STORE: LazyLock<RwLock<HashMap<String, time::Instant>>> =
+static RESET_TIME_STORE: LazyLock<RwLock<HashMap<String, LocalEntry>>> =
     LazyLock::new(|| RwLock::new(HashMap::new()));
 
+/// Reset time of a key in the local cache, and when it was cached.
+#[cfg(feature = "local_accelerate")]
+#[derive(Debug, Clone, Copy)]
+struct LocalEntry {
+    reset_time: time::Instant,
+    cached_at: time::Instant,
+}
+
+/// Cache the reset time of a key checked with Redis.
+///
+/// Entries which are reset within `LOCAL_CACHE_MIN_RESET_AFTER` are stale almost right away,
+/// so they are not cached, which saves the write lock for short window limits.
+/// The previous entry of the key is dropped instead, since it's older than the Redis result.
+#[cfg(feature = "local_accelerate")]
+fn cache_reset_time(raw_key: &str, now: time::Instant, reset_after: time::Duration) {
+    if reset_after < LOCAL_CACHE_MIN_RESET_AFTER {
+        let cached = RESET_TIME_STORE
+            .try_read()
+            .is_ok_and(|store| store.contains_key(raw_key));
+        if cached && let Ok(mut store) = RESET_TIME_STORE.try_write() {
+            store.remove(raw_key);
+        }
+        return;
+    }
+    if let Ok(mut store) = RESET_TIME_STORE.try_write() {
+        store.insert(
+            raw_key.to_string(),
+            LocalEntry {
+                reset_time: now + reset_after,
+                cached_at: now,
+            },
+        );
+    }
+}
+
 const DEFAULT_LIMITER_KEY_PREFIX: &str = "redis_rate:";
+const LIMIT_FINGERPRINT_SEPARATOR: char = '#';
+const EXPORT_BATCH_SIZE: usize = 100;
+#[cfg(feature = "local_accelerate")]
+const DEFAULT_LOCAL_CACHE_TTL: time::Duration = time::Duration::from_secs(60);
+#[cfg(feature = "local_accelerate")]
+const LOCAL_CACHE_MIN_RESET_AFTER: time::Duration = time::Duration::from_secs(1);
+const DEFAULT_MAX_KEY_LENGTH: usize = 1024;
+const DEFAULT_TRY_TIMEOUT: time::Duration = time::Duration::from_millis(10);
+const DEFAULT_MAX_PLAUSIBLE_DURATION: time::Duration = time::Duration::from_secs(3 * 24 * 60 * 60);
+/// Jan 1, 2017 00:00:00 GMT, the default epoch of the times in the GCRA scripts.
+const JAN_1_2017: u64 = 1483228800;
 
 #[cfg(feature = "local_accelerate")]
 const DEFAULT_LIMITER_EVENT_CHANNEL: &str = "redis_rate_channel";
 #[cfg(feature = "local_accelerate")]
-const LIMITER_RESET_EVENT_PREFIX: &str = "reset:";
+const EVENT_SYNC_INITIAL_BACKOFF: time::Duration = time::Duration::from_millis(100);
+#[cfg(feature = "local_accelerate")]
+const DEFAULT_EVENT_SYNC_MAX_BACKOFF: time::Duration = time::Duration::from_secs(30);
 
 /// Rate limit setting.
-#[derive(Debug, Clone)]
+///
+/// `rate` requests are allowed per `period_seconds`, evenly spaced,
+/// and `burst` is the total number of requests which can be made at once, including `rate`.
+/// So `remaining` is at most `burst`, and with `rate == burst` there is no extra burst
+/// beyond the requests of one period.
+///
+/// Coming from token buckets, where the burst is extra capacity on top of the rate,
+/// use `Limit::with_extra_burst`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Limit {
     rate: usize,
     burst: usize,
@@ -31,30 +144,183 @@ pub struct Limit {
 
 impl Limit {
     /// Create a new `Limit` setting.
-    /// Code will panic if you try to create a limit with invalid values.
+    /// Code will panic if you try to create a limit with invalid values,
+    /// which is fine for limits from the config at startup.
+    ///
+    /// For limits from runtime data, use `try_new` to handle invalid values as an error,
+    /// or `checked_new` to clamp them into a valid limit.
     pub fn new(rate: usize, burst: usize, period_seconds: usize) -> Self {
+        match Limit::try_new(rate, burst, period_seconds) {
+            Ok(limit) => limit,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Create a new `Limit` setting,
+    /// or return `RateLimitError::InvalidLimit` if the values are invalid.
+    pub fn try_new(
+        rate: usize,
+        burst: usize,
+        period_seconds: usize,
+    ) -> Result<Self, RateLimitError> {
         if period_seconds == 0 {
-            panic!("period_seconds must be greater than 0");
+            return Err(RateLimitError::InvalidLimit(
+                "period_seconds must be greater than 0",
+            ));
         }
         if rate == 0 {
-            panic!("rate must be greater than 0");
+            return Err(RateLimitError::InvalidLimit("rate must be greater than 0"));
         }
         if rate > burst {
-            panic!("rate must be less than or equal to burst");
+            return Err(RateLimitError::InvalidLimit(
+                "rate must be less than or equal to burst",
+            ));
         }
 
-        Limit {
+        Ok(Limit {
             rate,
             burst,
             period_seconds,
+        })
+    }
+
+    /// Create a new `Limit` setting, clamping invalid values instead of failing:
+    /// a zero rate or period becomes 1, and a burst smaller than the rate becomes the rate.
+    ///
+    /// This never panics, for teams which prefer a degraded limit over a crash,
+    /// but the clamping is silent, so check the values with `try_new` where a mistake matters.
+    pub fn checked_new(rate: usize, burst: usize, period_seconds: usize) -> Self {
+        let rate = rate.max(1);
+        Limit {
+            rate,
+            burst: burst.max(rate),
+            period_seconds: period_seconds.max(1),
+        }
+    }
+
+    /// Create a new `Limit` setting with `extra` requests of burst on top of `rate`,
+    /// i.e. `burst = rate + extra`, like a token bucket.
+    /// Code will panic if you try to create a limit with invalid values.
+    pub fn with_extra_burst(rate: usize, period_seconds: usize, extra: usize) -> Self {
+        let burst = rate
+            .checked_add(extra)
+            .expect("rate + extra must not overflow");
+        Limit::new(rate, burst, period_seconds)
+    }
+
+    /// How often one request is replenished, `period_seconds / rate`,
+    /// saturated to `Duration::MAX` for a period too long to be represented.
+    pub fn emission_interval(&self) -> time::Duration {
+        time::Duration::try_from_secs_f64(self.period_seconds as f64 / self.rate as f64)
+            .unwrap_or(time::Duration::MAX)
+    }
+
+    /// Simulate single requests at the given times in seconds, with no Redis involved,
+    /// and return whether each request is allowed.
+    /// The times should be non-decreasing, the state starts empty.
+    ///
+    /// This runs `gcra_decide` like the in-memory limiter, to validate limits offline.
+    pub fn simulate(&self, requests_at: impl IntoIterator<Item = f64>) -> Vec<bool> {
+        let emission_interval = self.period_seconds as f64 / self.rate as f64;
+        let burst_offset = self.burst as f64 * emission_interval;
+        let mut tat = f64::MIN;
+        requests_at
+            .into_iter()
+            .map(|now| {
+                let (limited, _, _, reset_after) = gcra_decide(
+                    now,
+                    tat.max(now),
+                    emission_interval,
+                    burst_offset,
+                    emission_interval,
+                );
+                if !limited {
+                    tat = now + reset_after;
+                }
+                !limited
+            })
+            .collect()
+    }
+
+    /// Fingerprint of the limit parameters, used in limit scoped keys.
+    fn fingerprint(&self) -> String {
+        format!("{}/{}/{}", self.rate, self.burst, self.period_seconds)
+    }
+
+    fn is_fingerprint(s: &str) -> bool {
+        let parts: Vec<&str> = s.split('/').collect();
+        parts.len() == 3 && parts.iter().all(|part| part.parse::<usize>().is_ok())
+    }
+}
+
+impl AsRef<Limit> for Limit {
+    fn as_ref(&self) -> &Limit {
+        self
+    }
+}
+
+/// Lets a `static` limit be passed as `&LIMIT`, like the one in the axum example.
+impl<F: FnOnce() -> Limit> AsRef<Limit> for std::sync::LazyLock<Limit, F> {
+    fn as_ref(&self) -> &Limit {
+        self
+    }
+}
+
+/// The GCRA parameters which the scripts consume,
+/// to tune the steady rate and the burst independently of a `Limit`,
+/// e.g. a tiny steady rate with a large one-time burst.
+///
+/// One request is replenished every `emission_interval`,
+/// and up to `burst_offset / emission_interval` requests can be made at once.
+/// A `Limit` converts into the equivalent `RawLimit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RawLimit {
+    /// How often one request is replenished, must not be zero.
+    pub emission_interval: time::Duration,
+    /// How much capacity can be used at once, in units of time.
+    pub burst_offset: time::Duration,
+}
+
+impl From<&Limit> for RawLimit {
+    fn from(limit: &Limit) -> Self {
+        // Both durations saturate for periods too long to be represented, like `emission_interval`.
+        let emission_interval = limit.period_seconds as f64 / limit.rate as f64;
+        RawLimit {
+            emission_interval: limit.emission_interval(),
+            burst_offset: time::Duration::try_from_secs_f64(limit.burst as f64 * emission_interval)
+                .unwrap_or(time::Duration::MAX),
         }
     }
 }
 
+impl From<Limit> for RawLimit {
+    fn from(limit: Limit) -> Self {
+        RawLimit::from(&limit)
+    }
+}
+
 /// Compile-time checked macro to create a new `Limit` instance.
 /// If you want to create dynamically configured limits, use `Limit::new` instead.
+///
+/// Arguments can be given positionally as `new_limit!(rate, burst, period_seconds)`,
+/// or by name in any order as `new_limit!(rate: 1, burst: 5, period_seconds: 10)`.
 #[macro_export]
 macro_rules! new_limit {
+    (@named [$($rate:expr)?] [$($burst:expr)?] [$($period_seconds:expr)?]) => {
+        $crate::new_limit!($($rate)?, $($burst)?, $($period_seconds)?)
+    };
+    (@named [] [$($burst:expr)?] [$($period_seconds:expr)?] rate: $value:expr $(, $($rest:tt)*)?) => {
+        $crate::new_limit!(@named [$value] [$($burst)?] [$($period_seconds)?] $($($rest)*)?)
+    };
+    (@named [$($rate:expr)?] [] [$($period_seconds:expr)?] burst: $value:expr $(, $($rest:tt)*)?) => {
+        $crate::new_limit!(@named [$($rate)?] [$value] [$($period_seconds)?] $($($rest)*)?)
+    };
+    (@named [$($rate:expr)?] [$($burst:expr)?] [] period_seconds: $value:expr $(, $($rest:tt)*)?) => {
+        $crate::new_limit!(@named [$($rate)?] [$($burst)?] [$value] $($($rest)*)?)
+    };
+    ($($name:ident: $value:expr),+ $(,)?) => {
+        $crate::new_limit!(@named [] [] [] $($name: $value),+)
+    };
     ($rate:expr, $burst:expr, $period_seconds:expr) => {{
         const _: () = {
             assert!($period_seconds > 0, "period_seconds must be greater than 0");
@@ -67,6 +333,7 @@ macro_rules! new_limit {
 
 /// Result of a limit check.
 #[derive(Debug, Clone)]
+#[must_use = "a rate-limit decision was computed but not acted on"]
 pub struct LimitResult {
     /// Whether the request is limited.
     pub limited: bool,
@@ -77,147 +344,2387 @@ pub struct LimitResult {
     pub retry_after: Option<time::Duration>,
     /// Duration after which the limit will be totally reset.
     pub reset_after: time::Duration,
+    /// Limit which the request was checked with, after any burst override,
+    /// so it may differ from the limit given to the check.
+    pub limit: Limit,
+}
+
+impl LimitResult {
+    fn from_raw(result: RawLimitResult, limit: &Limit) -> Self {
+        LimitResult {
+            limited: result.limited,
+            remaining: result.remaining,
+            retry_after: result.retry_after,
+            reset_after: result.reset_after,
+            limit: limit.clone(),
+        }
+    }
+
+    /// How often one request is replenished by the limit, `period_seconds / rate`,
+    /// so clients can pace themselves without knowing the full limit.
+    pub fn emission_interval(&self) -> time::Duration {
+        self.limit.emission_interval()
+    }
+
+    /// Wall clock time after which the request can be retried.
+    /// If the request is not limited, this will be `None`.
+    ///
+    /// This is anchored to the local clock when called, so call it right after the check.
+    pub fn retry_at(&self) -> Option<time::SystemTime> {
+        if !self.limited {
+            return None;
+        }
+        self.retry_after
+            .map(|retry_after| time::SystemTime::now() + retry_after)
+    }
+
+    /// Wall clock time after which the limit will be totally reset.
+    ///
+    /// This is anchored to the local clock when called, so call it right after the check.
+    pub fn reset_at(&self) -> time::SystemTime {
+        time::SystemTime::now() + self.reset_after
+    }
+
+    /// Framework agnostic parts of an HTTP response for the result,
+    /// the status code and the rate limit headers.
+    ///
+    /// The status is `200` if the request is allowed, and `429` if it is limited.
+    /// The headers are `X-RateLimit-Limit` with the burst of the limit,
+    /// `X-RateLimit-Remaining`, `X-RateLimit-Reset` with the seconds until the limit is totally reset,
+    /// and `Retry-After` in seconds if the request is limited. Seconds are rounded up.
+    pub fn to_http_parts(&self) -> (u16, Vec<(String, String)>) {
+        let ceil_secs = |d: time::Duration| d.as_secs() + u64::from(d.subsec_nanos() > 0);
+        let mut headers = vec![
+            (
+                "X-RateLimit-Limit".to_string(),
+                self.limit.burst.to_string(),
+            ),
+            (
+                "X-RateLimit-Remaining".to_string(),
+                self.remaining.to_string(),
+            ),
+            (
+                "X-RateLimit-Reset".to_string(),
+                ceil_secs(self.reset_after).to_string(),
+            ),
+        ];
+        if !self.limited {
+            return (200, headers);
+        }
+        let retry_after = self.retry_after.unwrap_or_default();
+        headers.push((
+            "Retry-After".to_string(),
+            ceil_secs(retry_after).to_string(),
+        ));
+        (429, headers)
+    }
+
+    /// View the result as an `Outcome`,
+    /// where `retry_after` is only available when the request is limited.
+    pub fn outcome(&self) -> Outcome {
+        if self.limited {
+            Outcome::Limited {
+                retry_after: self.retry_after.unwrap_or_default(),
+                remaining: self.remaining,
+            }
+        } else {
+            Outcome::Allowed {
+                remaining: self.remaining,
+            }
+        }
+    }
+}
+
+/// Result of a limit check, with details about the state of the key.
+#[derive(Debug, Clone)]
+#[must_use = "a rate-limit decision was computed but not acted on"]
+pub struct VerboseLimitResult {
+    /// Result of the limit check.
+    pub result: LimitResult,
+    /// Whether the key had no state before this check,
+    /// i.e. this is the first request of the key since it was reset or expired.
+    pub first_seen: bool,
+    /// Number of requests granted by this check, 0 if the request is limited.
+    pub granted: usize,
+}
+
+/// Result of a check with a `RawLimit`, like `LimitResult` without the limit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[must_use = "a rate-limit decision was computed but not acted on"]
+pub struct RawLimitResult {
+    /// Whether the request is limited.
+    pub limited: bool,
+    /// Remaining requests that can be made within the limit.
+    pub remaining: usize,
+    /// Duration after which the request can be retried.
+    /// If the request is not limited, this will be `None`.
+    pub retry_after: Option<time::Duration>,
+    /// Duration after which the limit will be totally reset.
+    pub reset_after: time::Duration,
+}
+
+impl RawLimitResult {
+    /// Decode the reply of the peek script.
+    /// `remaining` is decoded as `u64` and saturated to `usize::MAX`,
+    /// so a giant burst behaves the same on 32-bit targets.
+    /// The durations are replied in whole microseconds, with a negative `retry_after` for none,
+    /// so they are decoded without a float round trip.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn from_script_reply(reply: &redis::Value) -> Result<Self, redis::RedisError> {
+        let (limited, remaining, retry_after_micros, reset_after_micros) =
+            redis::from_redis_value(reply)?;
+        Ok(RawLimitResult::from_reply_parts(
+            limited,
+            remaining,
+            retry_after_micros,
+            reset_after_micros,
+        ))
+    }
+
+    /// Decode the reply of the allow and fixed window scripts,
+    /// which also tell whether the key is first seen and how many requests are granted.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn from_verbose_script_reply(
+        reply: &redis::Value,
+    ) -> Result<(Self, bool, usize), redis::RedisError> {
+        let (limited, remaining, retry_after_micros, reset_after_micros, first_seen, granted): (
+            bool,
+            u64,
+            i64,
+            i64,
+            bool,
+            u64,
+        ) = redis::from_redis_value(reply)?;
+        let result = RawLimitResult::from_reply_parts(
+            limited,
+            remaining,
+            retry_after_micros,
+            reset_after_micros,
+        );
+        Ok((
+            result,
+            first_seen,
+            usize::try_from(granted).unwrap_or(usize::MAX),
+        ))
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn from_reply_parts(
+        limited: bool,
+        remaining: u64,
+        retry_after_micros: i64,
+        reset_after_micros: i64,
+    ) -> Self {
+        RawLimitResult {
+            limited,
+            remaining: usize::try_from(remaining).unwrap_or(usize::MAX),
+            retry_after: u64::try_from(retry_after_micros)
+                .ok()
+                .map(time::Duration::from_micros),
+            reset_after: time::Duration::from_micros(reset_after_micros.max(0) as u64),
+        }
+    }
+
+    /// Build the result from the outputs of `gcra_decide`.
+    fn from_decision(
+        (limited, remaining, retry_after_secs, reset_after_secs): (bool, usize, f64, f64),
+    ) -> Self {
+        let retry_after = if retry_after_secs < 0.0 {
+            None
+        } else {
+            Some(time::Duration::try_from_secs_f64(retry_after_secs).unwrap_or(time::Duration::MAX))
+        };
+
+        RawLimitResult {
+            limited,
+            remaining,
+            retry_after,
+            reset_after: time::Duration::try_from_secs_f64(reset_after_secs)
+                .unwrap_or(time::Duration::MAX),
+        }
+    }
+}
+
+impl VerboseLimitResult {
+    /// Limit which the request was actually checked with, after any burst override,
+    /// e.g. to log why the limit of a premium user differs from the base one.
+    pub fn effective_limit(&self) -> &Limit {
+        &self.result.limit
+    }
+}
+
+/// Outcome of a limit check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The request is allowed.
+    Allowed {
+        /// Remaining requests that can be made within the limit.
+        remaining: usize,
+    },
+    /// The request is limited.
+    Limited {
+        /// Duration after which the request can be retried.
+        retry_after: time::Duration,
+        /// Remaining requests that can be made within the limit.
+        remaining: usize,
+    },
+}
+
+/// How to handle Redis failures when checking limits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FailureMode {
+    /// Return the Redis error to the caller.
+    #[default]
+    Error,
+    /// Allow the request, with no `remaining` requests.
+    Open,
+    /// Limit the request, which can be retried after one emission interval.
+    Closed,
+}
+
+impl FailureMode {
+    /// Turn a failed limit check into a result according to the mode.
+    fn handle(
+        self,
+        result: Result<LimitResult, RedisError>,
+        limit: &Limit,
+    ) -> Result<LimitResult, RedisError> {
+        let Err(e) = result else {
+            return result;
+        };
+        self.handle_raw(Err(e), limit.emission_interval())
+            .map(|result| LimitResult::from_raw(result, limit))
+    }
+
+    /// Turn a failed check with the emission interval into a result according to the mode.
+    fn handle_raw(
+        self,
+        result: Result<RawLimitResult, RedisError>,
+        emission_interval: time::Duration,
+    ) -> Result<RawLimitResult, RedisError> {
+        let Err(e) = result else {
+            return result;
+        };
+        match self {
+            FailureMode::Error => Err(e),
+            FailureMode::Open => Ok(RawLimitResult {
+                limited: false,
+                remaining: 0,
+                retry_after: None,
+                reset_after: time::Duration::ZERO,
+            }),
+            FailureMode::Closed => Ok(RawLimitResult {
+                limited: true,
+                remaining: 0,
+                retry_after: Some(emission_interval),
+                reset_after: emission_interval,
+            }),
+        }
+    }
+}
+
+/// How the expiry of the stored state is rounded to whole seconds.
+///
+/// Rounding down expires keys before the limit is totally reset,
+/// which grants a bit of extra burst, so `Ceil` is the safe default.
+/// The expiry is always at least one second.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TtlRounding {
+    /// Round up, keys never expire early.
+    #[default]
+    Ceil,
+    /// Round to the nearest second.
+    Round,
+    /// Round down, keys never stay longer than needed.
+    Floor,
+}
+
+impl TtlRounding {
+    /// The name of the mode passed to the scripts.
+    fn as_str(self) -> &'static str {
+        match self {
+            TtlRounding::Ceil => "ceil",
+            TtlRounding::Round => "round",
+            TtlRounding::Floor => "floor",
+        }
+    }
+}
+
+/// Algorithm used by `allow_n` to check limits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Algorithm {
+    /// The generic cell rate algorithm.
+    #[default]
+    Gcra,
+    /// Count the requests in fixed windows of `period_seconds`,
+    /// allowing `rate` requests per window, while `burst` is not used.
+    ///
+    /// The counter of each window is stored under `{key}:{window start}`,
+    /// where the window start is in seconds since the Unix epoch,
+    /// and kept for `retention` after the window ends so it can be read by `window_count`.
+    /// `reset` clears all the counters of the key, and `refund_n` only gives back requests
+    /// of the current window. The in-memory limiter doesn't support it.
+    ///
+    /// The key of the counter is derived in the script from the Redis time,
+    /// so it isn't declared to Redis, and this doesn't work with Redis Cluster.
+    /// Not available on wasm32, where only the in-memory limiter is built.
+    #[cfg(not(target_arch = "wasm32"))]
+    FixedWindow {
+        /// How long the counter of a window is kept after the window ends.
+        retention: time::Duration,
+    },
+}
+
+/// Error of the limiter operations which can be limited.
+#[derive(Debug)]
+pub enum RateLimitError {
+    /// The request is limited, the details are in the result.
+    Limited(LimitResult),
+    /// Error returned by Redis.
+    #[cfg(not(target_arch = "wasm32"))]
+    Redis(redis::RedisError),
+    /// No default limit is set on the limiter.
+    MissingDefaultLimit,
+    /// The result has a duration too long to be plausible,
+    /// the Redis clock is off or the stored state is corrupted.
+    /// See `Limiter::set_max_plausible_duration`.
+    ImplausibleResult(LimitResult),
+    /// No connection to Redis could be made within the try timeout of `try_allow_n`,
+    /// since it timed out or was refused.
+    #[cfg(not(target_arch = "wasm32"))]
+    WouldBlock(redis::RedisError),
+    /// The key is empty, whitespace only, or longer than the max key length,
+    /// with the reason. See `Limiter::set_max_key_length`.
+    InvalidKey(&'static str),
+    /// The limit is invalid, with the reason, e.g. a burst override smaller than the rate.
+    InvalidLimit(&'static str),
+}
+
+impl fmt::Display for RateLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RateLimitError::Limited(_) => write!(f, "rate limited"),
+            #[cfg(not(target_arch = "wasm32"))]
+            RateLimitError::Redis(e) => write!(f, "redis error: {}", e),
+            RateLimitError::MissingDefaultLimit => write!(f, "no default limit is set"),
+            RateLimitError::ImplausibleResult(result) => write!(
+                f,
+                "implausible limit result, retry after {:?}, reset after {:?}",
+                result.retry_after, result.reset_after
+            ),
+            #[cfg(not(target_arch = "wasm32"))]
+            RateLimitError::WouldBlock(e) => write!(f, "no redis connection available: {}", e),
+            RateLimitError::InvalidKey(reason) => write!(f, "invalid key: {}", reason),
+            RateLimitError::InvalidLimit(reason) => write!(f, "invalid limit: {}", reason),
+        }
+    }
+}
+
+impl error::Error for RateLimitError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            RateLimitError::Redis(e) | RateLimitError::WouldBlock(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl RateLimitError {
+    /// Whether Redis couldn't be reached in time, e.g. the connection is refused, dropped or timed out.
+    /// Web handlers can answer these with 503 and a `Retry-After`,
+    /// and the other errors, e.g. a script or decoding error, with 500.
+    pub fn is_unavailable(&self) -> bool {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            RateLimitError::WouldBlock(_) => true,
+            #[cfg(not(target_arch = "wasm32"))]
+            RateLimitError::Redis(e) => {
+                e.is_connection_refusal()
+                    || e.is_connection_dropped()
+                    || e.is_timeout()
+                    || e.is_io_error()
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl From<redis::RedisError> for RateLimitError {
+    fn from(e: redis::RedisError) -> Self {
+        RateLimitError::Redis(e)
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+impl From<RedisError> for RateLimitError {
+    fn from(e: RedisError) -> Self {
+        match e {}
+    }
+}
+
+/// Where the limiter stores its state.
+/// Only the in-memory backend is built on wasm32.
+#[derive(Debug, Clone)]
+enum Backend {
+    #[cfg(not(target_arch = "wasm32"))]
+    Redis(redis::Client),
+    Memory(Arc<MemoryStore>),
 }
 
+/// Connection of the checks shared by both backends, which never exists on wasm32.
+#[cfg(not(target_arch = "wasm32"))]
+type Connection = redis::Connection;
+#[cfg(target_arch = "wasm32")]
+enum Connection {}
+
+/// Error of the checks shared by both backends, which can't happen on wasm32
+/// since the in-memory backend doesn't fail.
+#[cfg(not(target_arch = "wasm32"))]
+type RedisError = redis::RedisError;
+#[cfg(target_arch = "wasm32")]
+#[derive(Debug)]
+enum RedisError {}
+
 /// Rate limiter backed by Redis.
+/// Cloning a limiter is cheap, since the clones share the same inner state.
 #[derive(Debug, Clone)]
 pub struct Limiter {
-    client: redis::Client,
+    inner: Arc<LimiterInner>,
+}
+
+#[derive(Debug, Clone)]
+struct LimiterInner {
+    backend: Backend,
     key_prefix: String,
+    namespace: Option<String>,
+    default_limit: Option<Limit>,
+    failure_mode: FailureMode,
+    limit_scoped_keys: bool,
+    wait_replicas: Option<(usize, time::Duration)>,
+    algorithm: Algorithm,
+    microsecond_time: bool,
+    max_plausible_duration: time::Duration,
+    ttl_rounding: TtlRounding,
+    try_timeout: time::Duration,
+    max_key_length: usize,
+    dry_run: bool,
+    dry_run_denials: Arc<AtomicU64>,
+    stats: Arc<StatsCounters>,
+    retry_jitter: time::Duration,
+    jitter_rng: JitterRng,
+    #[cfg(feature = "functions")]
+    use_functions: bool,
 
     #[cfg(feature = "local_accelerate")]
     event_channel: String,
+    #[cfg(feature = "local_accelerate")]
+    event_sync_max_backoff: time::Duration,
+    #[cfg(feature = "local_accelerate")]
+    publish_consume_events: bool,
+    #[cfg(feature = "local_accelerate")]
+    local_cache_ttl: time::Duration,
+    #[cfg(feature = "local_accelerate")]
+    reset_events_processed: Arc<AtomicU64>,
+    /// Milliseconds since the Unix epoch, 0 if no reset event is processed.
+    #[cfg(feature = "local_accelerate")]
+    last_reset_event_at: Arc<AtomicU64>,
 }
 
 impl Limiter {
     /// Create a new limiter with the given Redis client.
+    /// Not available on wasm32, where only the in-memory limiter is built.
+    #[cfg(not(target_arch = "wasm32"))]
     pub fn new(client: redis::Client) -> Self {
-        Limiter {
-            client,
+        Self::with_backend(Backend::Redis(client))
+    }
+
+    /// Create a new limiter which keeps its state in process memory, with no network at all.
+    /// This is useful for CLIs and tests, which don't need to share limits across instances.
+    ///
+    /// Checking, peeking, refunding and resetting limits work the same as with Redis,
+    /// while operations which need a Redis connection return an error.
+    pub fn in_memory() -> Self {
+        Self::with_backend(Backend::Memory(Arc::new(MemoryStore::default())))
+    }
+
+    fn with_backend(backend: Backend) -> Self {
+        let inner = LimiterInner {
+            backend,
             key_prefix: DEFAULT_LIMITER_KEY_PREFIX.to_string(),
+            namespace: None,
+            default_limit: None,
+            failure_mode: FailureMode::default(),
+            limit_scoped_keys: false,
+            wait_replicas: None,
+            algorithm: Algorithm::default(),
+            microsecond_time: false,
+            max_plausible_duration: DEFAULT_MAX_PLAUSIBLE_DURATION,
+            ttl_rounding: TtlRounding::default(),
+            try_timeout: DEFAULT_TRY_TIMEOUT,
+            max_key_length: DEFAULT_MAX_KEY_LENGTH,
+            dry_run: false,
+            dry_run_denials: Arc::new(AtomicU64::new(0)),
+            stats: Arc::new(StatsCounters::default()),
+            retry_jitter: time::Duration::ZERO,
+            jitter_rng: JitterRng::default(),
+            #[cfg(feature = "functions")]
+            use_functions: false,
 
             #[cfg(feature = "local_accelerate")]
             event_channel: DEFAULT_LIMITER_EVENT_CHANNEL.to_string(),
+            #[cfg(feature = "local_accelerate")]
+            event_sync_max_backoff: DEFAULT_EVENT_SYNC_MAX_BACKOFF,
+            #[cfg(feature = "local_accelerate")]
+            publish_consume_events: false,
+            #[cfg(feature = "local_accelerate")]
+            local_cache_ttl: DEFAULT_LOCAL_CACHE_TTL,
+            #[cfg(feature = "local_accelerate")]
+            reset_events_processed: Arc::new(AtomicU64::new(0)),
+            #[cfg(feature = "local_accelerate")]
+            last_reset_event_at: Arc::new(AtomicU64::new(0)),
+        };
+        Limiter {
+            inner: Arc::new(inner),
+        }
+    }
+
+    /// The epoch and the units per second of the times in the GCRA scripts.
+    fn script_time(&self) -> (u64, f64) {
+        if self.inner.microsecond_time {
+            (0, 1_000_000.0)
+        } else {
+            (JAN_1_2017, 1.0)
+        }
+    }
+
+    /// Convert seconds to the time unit of the GCRA scripts.
+    fn to_script_time(&self, secs: f64) -> f64 {
+        if self.inner.microsecond_time {
+            (secs * 1_000_000.0).round()
+        } else {
+            secs
+        }
+    }
+
+    /// Get the Redis client, or an error for the in-memory limiter.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn client(&self) -> Result<&redis::Client, redis::RedisError> {
+        match &self.inner.backend {
+            Backend::Redis(client) => Ok(client),
+            Backend::Memory(_) => Err(redis::RedisError::from((
+                redis::ErrorKind::ClientError,
+                "not supported by the in-memory limiter",
+            ))),
         }
     }
 
     /// Set the key prefix for the limiter's Redis keys.
+    #[must_use]
     pub fn set_key_prefix(mut self, key_prefix: &str) -> Self {
-        self.key_prefix = key_prefix.to_string();
+        Arc::make_mut(&mut self.inner).key_prefix = key_prefix.to_string();
         self
     }
 
-    /// Set the event channel name for the limiter.
-    /// This should be called before `start_event_sync`.
-    #[cfg(feature = "local_accelerate")]
-    pub fn set_event_channel(mut self, channel: &str) -> Self {
-        self.event_channel = channel.to_string();
+    /// Set a namespace, e.g. the environment, to add between the key prefix and the keys.
+    ///
+    /// Keys are then stored as `{key_prefix}{namespace}:{key}`,
+    /// and `reset`, `reset_pattern` and `keys` only see the keys of the namespace,
+    /// so limiters of different namespaces can share one Redis and one key prefix.
+    #[must_use]
+    pub fn set_namespace(mut self, namespace: &str) -> Self {
+        Arc::make_mut(&mut self.inner).namespace = Some(namespace.to_string());
         self
     }
 
-    /// Start a listening loop on the event channel.
-    /// When reset event is triggered on other instances, the limiter will reset the local cache for the key.
-    #[cfg(feature = "local_accelerate")]
-    pub fn start_event_sync(&self) -> Result<(), redis::RedisError> {
-        let mut con = self.client.get_connection()?;
-        let mut pubsub = con.as_pubsub();
-        pubsub.subscribe(&self.event_channel).unwrap();
-        loop {
-            let msg = pubsub.get_message()?.get_payload::<String>()?;
-            if msg.starts_with(LIMITER_RESET_EVENT_PREFIX) {
-                let payload = msg.split_at(LIMITER_RESET_EVENT_PREFIX.len()).1;
-                if let Ok(mut store) = RESET_TIME_STORE.try_write() {
-                    store.remove(payload);
-                }
-            }
+    /// The prefix of all the Redis keys of the limiter, with the namespace if any.
+    fn prefix(&self) -> Cow<'_, str> {
+        match &self.inner.namespace {
+            Some(namespace) => Cow::Owned(format!("{}{}:", self.inner.key_prefix, namespace)),
+            None => Cow::Borrowed(&self.inner.key_prefix),
         }
     }
 
-    /// Reset the limit for a key.
-    pub fn reset(&self, key: &str) -> Result<(), redis::RedisError> {
-        let key = format!("{}{}", self.key_prefix, key);
-        let mut con = self.client.get_connection()?;
-        redis::cmd("DEL").arg(&key).query::<()>(&mut con)?;
+    /// Set the default limit used by `allow_default` and `allow_n_default`.
+    #[must_use]
+    pub fn set_default_limit(mut self, limit: Limit) -> Self {
+        Arc::make_mut(&mut self.inner).default_limit = Some(limit);
+        self
+    }
 
-        #[cfg(feature = "local_accelerate")]
-        {
-            let reset_notify = format!("{}{}", LIMITER_RESET_EVENT_PREFIX, key);
-            redis::cmd("PUBLISH")
-                .arg(self.event_channel.clone())
-                .arg(&reset_notify)
-                .query::<()>(&mut con)?;
-        }
+    /// Set how the limiter handles Redis failures when checking limits.
+    /// It can be overridden per call with `allow_n_with_policy`.
+    #[must_use]
+    pub fn set_failure_mode(mut self, failure_mode: FailureMode) -> Self {
+        Arc::make_mut(&mut self.inner).failure_mode = failure_mode;
+        self
+    }
 
-        Ok(())
+    /// Store the state of a key separately for each limit it is checked with,
+    /// by appending a fingerprint of the limit to the Redis key.
+    ///
+    /// Without this, checking one key with limits of different periods shares one state
+    /// and gives inconsistent results. With this, each limit used on a key creates its own Redis key.
+    /// `reset` clears all the limit scoped keys of a key.
+    #[must_use]
+    pub fn set_limit_scoped_keys(mut self, enabled: bool) -> Self {
+        Arc::make_mut(&mut self.inner).limit_scoped_keys = enabled;
+        self
     }
 
-    /// Allow a request to be made within the limit.
-    pub fn allow(&self, key: &str, limit: &Limit) -> Result<LimitResult, redis::RedisError> {
-        self.allow_n(key, limit, 1)
+    /// Wait for the given number of replicas to acknowledge the write,
+    /// with `WAIT`, after each allowed request which is stored in Redis.
+    ///
+    /// This narrows the window where a failover loses consumed requests,
+    /// at the cost of a replication round trip on every allowed request.
+    /// Limited requests don't write, so they never wait.
+    /// If fewer replicas acknowledge before the timeout, the result is still returned.
+    #[must_use]
+    pub fn set_wait_replicas(mut self, replicas: usize, timeout: time::Duration) -> Self {
+        Arc::make_mut(&mut self.inner).wait_replicas = Some((replicas, timeout));
+        self
     }
 
-    /// Allow n requests to be made within the limit.
-    pub fn allow_n(
-        &self,
-        key: &str,
-        limit: &Limit,
-        n: usize,
-    ) -> Result<LimitResult, redis::RedisError> {
-        let key = format!("{}{}", self.key_prefix, key);
+    /// Set the algorithm used by `allow_n` to check limits.
+    #[must_use]
+    pub fn set_algorithm(mut self, algorithm: Algorithm) -> Self {
+        Arc::make_mut(&mut self.inner).algorithm = algorithm;
+        self
+    }
 
-        let emission_interval = limit.period_seconds as f64 / limit.rate as f64;
-        let tat_increment = emission_interval * n as f64;
-        let brust_offset = limit.burst as f64 * emission_interval;
+    /// Keep the GCRA state in Redis as integer microseconds since the Unix epoch,
+    /// instead of fractional seconds since Jan 1, 2017.
+    ///
+    /// Integer microseconds are exact in a double, so they don't need the shifted epoch
+    /// to stay precise and are good until the year 2255.
+    /// The two formats are not compatible, so reset the existing keys before switching.
+    #[must_use]
+    pub fn set_microsecond_time(mut self, enabled: bool) -> Self {
+        Arc::make_mut(&mut self.inner).microsecond_time = enabled;
+        self
+    }
 
-        #[cfg(feature = "local_accelerate")]
-        let now = time::Instant::now();
-        #[cfg(feature = "local_accelerate")]
-        if let Ok(store) = RESET_TIME_STORE.try_read() {
-            if let Some(reset_time) = store.get(&key) {
-                let reset_after = reset_time.duration_since(now).as_secs_f64();
-                let diff: f64 = reset_after + tat_increment - brust_offset;
-                if diff > 0.0 {
-                    return Ok(LimitResult {
-                        limited: true,
-                        remaining: f64::floor((brust_offset - reset_after) / emission_interval)
-                            as usize,
-                        retry_after: Some(time::Duration::from_secs_f64(diff.abs())),
-                        reset_after: reset_time.duration_since(now),
-                    });
-                }
-            }
-        }
+    /// Call the GCRA logic as a Redis Function with `FCALL`, instead of a script with `EVALSHA`.
+    /// The function library must be loaded with `load_function` first, it survives `SCRIPT FLUSH`.
+    /// The results are identical to the scripts.
+    #[cfg(feature = "functions")]
+    #[must_use]
+    pub fn set_use_functions(mut self, enabled: bool) -> Self {
+        Arc::make_mut(&mut self.inner).use_functions = enabled;
+        self
+    }
 
-        let mut con = self.client.get_connection()?;
-        let result: redis::Value = ALLOW_N_SCRIPT
-            .key(&key)
-            .arg(emission_interval)
-            .arg(brust_offset)
-            .arg(tat_increment)
-            .arg(n)
-            .invoke(&mut con)?;
+    /// Load the GCRA logic as a Redis Function library with `FUNCTION LOAD REPLACE`.
+    /// This needs Redis 7 or later, and should be called before using `set_use_functions`.
+    #[cfg(feature = "functions")]
+    pub fn load_function(&self) -> Result<(), redis::RedisError> {
+        let mut con = self.client()?.get_connection()?;
+        redis::cmd("FUNCTION")
+            .arg("LOAD")
+            .arg("REPLACE")
+            .arg(FUNCTION_LIBRARY.as_str())
+            .query::<()>(&mut con)
+    }
 
-        let (limited, remaining, retry_after_secs, reset_after_secs): (bool, usize, f64, f64) =
-            redis::from_redis_value(&result)?;
-        let retry_after = if retry_after_secs < 0.0 {
-            None
-        } else {
-            Some(time::Duration::from_secs_f64(retry_after_secs))
-        };
-        let reset_after = time::Duration::from_secs_f64(reset_after_secs);
+    /// Set the maximum plausible `retry_after` and `reset_after` of the results, 3 days by default.
+    /// Checks with longer durations return `RateLimitError::ImplausibleResult`,
+    /// unless the limit itself can produce them, e.g. with a burst of a few weeks.
+    #[must_use]
+    pub fn set_max_plausible_duration(mut self, max: time::Duration) -> Self {
+        Arc::make_mut(&mut self.inner).max_plausible_duration = max;
+        self
+    }
 
+    /// Set the timeout of `try_allow_n` for connecting to Redis and running the check.
+    /// The timeout must not be zero.
+    #[must_use]
+    pub fn set_try_timeout(mut self, timeout: time::Duration) -> Self {
+        Arc::make_mut(&mut self.inner).try_timeout = timeout;
+        self
+    }
+
+    /// Set how the expiry of the stored state is rounded to whole seconds.
+    /// See `TtlRounding` for the trade-offs.
+    #[must_use]
+    pub fn set_ttl_rounding(mut self, ttl_rounding: TtlRounding) -> Self {
+        Arc::make_mut(&mut self.inner).ttl_rounding = ttl_rounding;
+        self
+    }
+
+    /// Set the max length of the keys in bytes, without the key prefix, 1024 by default.
+    /// Longer keys are rejected with `RateLimitError::InvalidKey` to protect the Redis memory.
+    #[must_use]
+    pub fn set_max_key_length(mut self, max: usize) -> Self {
+        Arc::make_mut(&mut self.inner).max_key_length = max;
+        self
+    }
+
+    /// Set whether the checks only observe the limit instead of enforcing it,
+    /// to roll out a new limit safely.
+    ///
+    /// In dry run, every check which would consume requests, e.g. `allow_n`, `allow_up_to`,
+    /// `allow_n_with_raw_limit` or a session, is made like `peek` with the algorithm of the limiter,
+    /// so no request is consumed and the stored state is never changed.
+    /// The returned result is never `limited` and grants all the requests,
+    /// but the rest of it is the real result,
+    /// e.g. `retry_after` is set if the requests would have been limited.
+    /// Since nothing is consumed, `with_permit` and `LimiterGroup` don't refund anything either.
+    /// Count the would-be denials with `dry_run_denials`.
+    #[must_use]
+    pub fn set_dry_run(mut self, enabled: bool) -> Self {
+        Arc::make_mut(&mut self.inner).dry_run = enabled;
+        self
+    }
+
+    /// Number of checks which would have been limited in dry run,
+    /// by this limiter and its clones.
+    pub fn dry_run_denials(&self) -> u64 {
+        self.inner.dry_run_denials.load(Ordering::Relaxed)
+    }
+
+    /// Add a random delay of up to `max` to the `retry_after` of limited checks,
+    /// so the clients limited at the same time don't all retry at once. 0 by default.
+    /// `reset_after` and the stored state are not changed.
+    #[must_use]
+    pub fn set_retry_jitter(mut self, max: time::Duration) -> Self {
+        Arc::make_mut(&mut self.inner).retry_jitter = max;
+        self
+    }
+
+    /// Set the source of random numbers in `[0, 1)` for the retry jitter,
+    /// e.g. a constant in tests to assert exact `retry_after` values.
+    /// Defaults to a fast thread-local generator.
+    #[must_use]
+    pub fn set_jitter_rng(mut self, rng: impl Fn() -> f64 + Send + Sync + 'static) -> Self {
+        Arc::make_mut(&mut self.inner).jitter_rng = JitterRng::new(rng);
+        self
+    }
+
+    /// Add the retry jitter to a `retry_after`.
+    fn jitter(&self, retry_after: Option<time::Duration>) -> Option<time::Duration> {
+        let jitter = self.inner.retry_jitter;
+        if jitter.is_zero() {
+            return retry_after;
+        }
+        retry_after.map(|d| d.saturating_add(jitter.mul_f64(self.inner.jitter_rng.sample())))
+    }
+
+    /// Counters of the checks made by this limiter and its clones since they were last reset,
+    /// which are cheap enough to log on a timer.
+    /// Checks in dry run are not counted at all, the would-be denials are in `dry_run_denials`.
+    pub fn stats_snapshot(&self) -> LimiterStats {
+        self.inner.stats.snapshot(false)
+    }
+
+    /// Get the counters like `stats_snapshot`, and reset them to 0,
+    /// e.g. to log the checks of every interval.
+    pub fn reset_stats(&self) -> LimiterStats {
+        self.inner.stats.snapshot(true)
+    }
+
+    /// Load the scripts into Redis with `SCRIPT LOAD`,
+    /// so the first requests don't have to send the script bodies.
+    /// Returns the SHA of the script run by `allow_n` with the configured algorithm.
+    ///
+    /// The sources of the scripts are exported as `ALLOW_N_SOURCE` and the like, for auditing.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn preload_script(&self) -> Result<String, redis::RedisError> {
+        let allow_source = match self.inner.algorithm {
+            Algorithm::Gcra => ALLOW_N_SOURCE,
+            Algorithm::FixedWindow { .. } => FIXED_WINDOW_SOURCE,
+        };
+        let mut con = self.client()?.get_connection()?;
+        let mut allow_sha = String::new();
+        for source in [
+            ALLOW_N_SOURCE,
+            PEEK_N_SOURCE,
+            REFUND_N_SOURCE,
+            FIXED_WINDOW_SOURCE,
+            FIXED_WINDOW_REFUND_SOURCE,
+        ] {
+            let sha: String = redis::cmd("SCRIPT")
+                .arg("LOAD")
+                .arg(source)
+                .query(&mut con)?;
+            if source == allow_source {
+                allow_sha = sha;
+            }
+        }
+        Ok(allow_sha)
+    }
+
+    /// Check that Redis is reachable with `PING` and the scripts can be loaded,
+    /// e.g. for a readiness probe, so a misconfiguration fails fast instead of on the first request.
+    /// With `set_use_functions`, the function library must be loaded too.
+    /// The in-memory limiter is always healthy.
+    pub fn health_check(&self) -> Result<(), RateLimitError> {
+        match &self.inner.backend {
+            Backend::Memory(_) => Ok(()),
+            #[cfg(not(target_arch = "wasm32"))]
+            Backend::Redis(client) => {
+                let mut con = client.get_connection()?;
+                redis::cmd("PING").query::<()>(&mut con)?;
+                self.preload_script()?;
+
+                #[cfg(feature = "functions")]
+                if self.inner.use_functions {
+                    let libraries: Vec<redis::Value> = redis::cmd("FUNCTION")
+                        .arg("LIST")
+                        .arg("LIBRARYNAME")
+                        .arg(FUNCTION_LIBRARY_NAME)
+                        .query(&mut con)?;
+                    if libraries.is_empty() {
+                        return Err(RateLimitError::Redis(redis::RedisError::from((
+                            redis::ErrorKind::ClientError,
+                            "the function library is not loaded, call load_function first",
+                        ))));
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Set the event channel name for the limiter.
+    /// This should be called before `start_event_sync`.
+    #[cfg(feature = "local_accelerate")]
+    #[must_use]
+    pub fn set_event_channel(mut self, channel: &str) -> Self {
+        Arc::make_mut(&mut self.inner).event_channel = channel.to_string();
+        self
+    }
+
+    /// Set the maximum delay between reconnect attempts of `start_event_sync`.
+    /// The delay starts from 100ms and doubles after each failed attempt.
+    #[cfg(feature = "local_accelerate")]
+    #[must_use]
+    pub fn set_event_sync_max_backoff(mut self, max_backoff: time::Duration) -> Self {
+        Arc::make_mut(&mut self.inner).event_sync_max_backoff = max_backoff;
+        self
+    }
+
+    /// Publish a consume event to the event channel after each allowed request,
+    /// so the other instances can update their local cache without asking Redis.
+    ///
+    /// This is off by default since it publishes a message for every allowed request.
+    /// With `Algorithm::FixedWindow`, only the request which fills the window publishes,
+    /// and the other instances limit the key until the window ends.
+    /// The other instances learn the new reset time eventually,
+    /// in the meantime they still make their decisions on their own cache or on Redis.
+    #[cfg(feature = "local_accelerate")]
+    #[must_use]
+    pub fn set_publish_consume_events(mut self, enabled: bool) -> Self {
+        Arc::make_mut(&mut self.inner).publish_consume_events = enabled;
+        self
+    }
+
+    /// Set how long a reset time in the local cache is trusted, 60 seconds by default.
+    /// Older entries are ignored and the next check asks Redis, even if no reset event came.
+    ///
+    /// This bounds how long instances can diverge, e.g. under clock drift,
+    /// at the cost of extra Redis calls for keys which stay limited.
+    #[cfg(feature = "local_accelerate")]
+    #[must_use]
+    pub fn set_local_cache_ttl(mut self, ttl: time::Duration) -> Self {
+        Arc::make_mut(&mut self.inner).local_cache_ttl = ttl;
+        self
+    }
+
+    /// Start a listening loop on the event channel.
+    /// When reset event is triggered on other instances, the limiter will reset the local cache for the key.
+    /// When consume event is triggered on other instances, the limiter will update the local cache for the key.
+    ///
+    /// If the connection is lost, the loop reconnects with backoff and subscribes again.
+    /// Since events may be missed meanwhile, the local cache is cleared after reconnecting.
+    /// Only errors which are not caused by the connection are returned.
+    #[cfg(feature = "local_accelerate")]
+    pub fn start_event_sync(&self) -> Result<(), redis::RedisError> {
+        let mut backoff = EVENT_SYNC_INITIAL_BACKOFF;
+        let mut reconnecting = false;
+        loop {
+            let err = match self
+                .client()?
+                .get_connection()
+                .and_then(|mut con| self.sync_events(&mut con, &mut backoff, reconnecting))
+            {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+            if !(err.is_connection_dropped()
+                || err.is_connection_refusal()
+                || err.is_io_error()
+                || err.is_timeout())
+            {
+                return Err(err);
+            }
+
+            reconnecting = true;
+            std::thread::sleep(backoff);
+            backoff = (backoff * 2).min(self.inner.event_sync_max_backoff);
+        }
+    }
+
+    /// Listen to the event channel on the connection until an error occurs.
+    #[cfg(feature = "local_accelerate")]
+    fn sync_events(
+        &self,
+        con: &mut redis::Connection,
+        backoff: &mut time::Duration,
+        reconnecting: bool,
+    ) -> Result<(), redis::RedisError> {
+        let mut pubsub = con.as_pubsub();
+        pubsub.subscribe(&self.inner.event_channel)?;
+        *backoff = EVENT_SYNC_INITIAL_BACKOFF;
+        if reconnecting && let Ok(mut store) = RESET_TIME_STORE.write() {
+            store.clear();
+        }
+
+        loop {
+            // The event borrows from the payload of the message,
+            // so only keys which are newly cached are allocated.
+            // The channel may receive stray messages,
+            // payloads which can't be decoded are skipped like unknown ones.
+            let msg = pubsub.get_message()?;
+            let Ok(payload) = std::str::from_utf8(msg.get_payload_bytes()) else {
+                continue;
+            };
+            match Event::decode(payload) {
+                Some(Event::Reset { key }) => {
+                    if let Ok(mut store) = RESET_TIME_STORE.try_write() {
+                        store.remove(key);
+                    }
+                    let now_millis = time::SystemTime::now()
+                        .duration_since(time::UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_millis();
+                    self.inner
+                        .last_reset_event_at
+                        .store(now_millis as u64, Ordering::Relaxed);
+                    self.inner
+                        .reset_events_processed
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+                Some(Event::Consume {
+                    key,
+                    reset_after_secs,
+                }) => {
+                    // Durations too large for an `Instant` are skipped like malformed payloads.
+                    let now = time::Instant::now();
+                    let Ok(reset_after) = time::Duration::try_from_secs_f64(reset_after_secs)
+                    else {
+                        continue;
+                    };
+                    let Some(reset_time) = now.checked_add(reset_after) else {
+                        continue;
+                    };
+                    if reset_after >= LOCAL_CACHE_MIN_RESET_AFTER
+                        && let Ok(mut store) = RESET_TIME_STORE.try_write()
+                    {
+                        if let Some(entry) = store.get_mut(key) {
+                            entry.reset_time = entry.reset_time.max(reset_time);
+                            entry.cached_at = now;
+                        } else {
+                            store.insert(
+                                key.to_string(),
+                                LocalEntry {
+                                    reset_time,
+                                    cached_at: now,
+                                },
+                            );
+                        }
+                    }
+                }
+                // Payloads from unknown formats are ignored.
+                None => {}
+            }
+        }
+    }
+
+    /// Number of reset events processed by `start_event_sync` of this limiter and its clones.
+    #[cfg(feature = "local_accelerate")]
+    pub fn reset_events_processed(&self) -> u64 {
+        self.inner.reset_events_processed.load(Ordering::Relaxed)
+    }
+
+    /// When the last reset event was processed by `start_event_sync` of this limiter and its clones.
+    /// If no reset event is processed yet, this will be `None`.
+    #[cfg(feature = "local_accelerate")]
+    pub fn last_reset_event_at(&self) -> Option<time::SystemTime> {
+        match self.inner.last_reset_event_at.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Some(time::UNIX_EPOCH + time::Duration::from_millis(millis)),
+        }
+    }
+
+    /// Reset the limit for a key.
+    /// Returns `RateLimitError::InvalidKey` if the key is invalid, see `set_max_key_length`.
+    pub fn reset(&self, key: &str) -> Result<(), RateLimitError> {
+        let raw_key = self.checked_raw_key(key)?;
+        match &self.inner.backend {
+            Backend::Memory(store) => {
+                let limit_scoped_keys = self.inner.limit_scoped_keys;
+                store.remove(|stored_key| {
+                    let Some(suffix) = stored_key.strip_prefix(raw_key.as_str()) else {
+                        return false;
+                    };
+                    suffix.is_empty()
+                        || limit_scoped_keys
+                            && suffix
+                                .strip_prefix(LIMIT_FINGERPRINT_SEPARATOR)
+                                .is_some_and(Limit::is_fingerprint)
+                });
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            Backend::Redis(client) => {
+                let mut con = client.get_connection()?;
+                if let Algorithm::FixedWindow { .. } = self.inner.algorithm {
+                    let pattern = format!("{}*", escape_glob_pattern(&raw_key));
+                    let window_keys: Vec<String> = con
+                        .scan_match::<_, String>(pattern)?
+                        .filter(|window_key| self.window_key_base(&raw_key, window_key).is_some())
+                        .collect();
+                    if !window_keys.is_empty() {
+                        redis::cmd("DEL").arg(&window_keys).query::<()>(&mut con)?;
+                    }
+                    // The limit scoped keys only exist as the bases of their window counters,
+                    // reset them too so their local cache entries are dropped everywhere.
+                    let mut bases: Vec<&str> = window_keys
+                        .iter()
+                        .filter_map(|window_key| self.window_key_base(&raw_key, window_key))
+                        .filter(|base| *base != raw_key)
+                        .collect();
+                    bases.sort_unstable();
+                    bases.dedup();
+                    for base in bases {
+                        self.reset_raw_with_conn(&mut con, base)?;
+                    }
+                }
+                if self.inner.limit_scoped_keys {
+                    let pattern = format!(
+                        "{}{}*",
+                        escape_glob_pattern(&raw_key),
+                        LIMIT_FINGERPRINT_SEPARATOR
+                    );
+                    let scoped_keys: Vec<String> = con
+                        .scan_match::<_, String>(pattern)?
+                        .filter(|scoped_key| {
+                            Limit::is_fingerprint(&scoped_key[raw_key.len() + 1..])
+                        })
+                        .collect();
+                    for scoped_key in scoped_keys {
+                        self.reset_raw_with_conn(&mut con, &scoped_key)?;
+                    }
+                }
+                self.reset_raw_with_conn(&mut con, &raw_key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The key whose window counter is the raw key, if it's a counter of the key
+    /// or, with limit scoped keys, of one of its limit scoped keys.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn window_key_base<'a>(&self, raw_key: &str, window_key: &'a str) -> Option<&'a str> {
+        let base = strip_window_start(window_key)?;
+        let suffix = base.strip_prefix(raw_key)?;
+        let is_base = suffix.is_empty()
+            || self.inner.limit_scoped_keys
+                && suffix
+                    .strip_prefix(LIMIT_FINGERPRINT_SEPARATOR)
+                    .is_some_and(Limit::is_fingerprint);
+        is_base.then_some(base)
+    }
+
+    /// Reset the limits of all the keys matching a glob pattern, e.g. `user:123:*`.
+    /// The pattern is matched against the keys without the key prefix, with `SCAN MATCH`,
+    /// and the number of reset keys is returned.
+    /// With `Algorithm::FixedWindow`, the pattern is matched against the keys of the counters
+    /// without the window start, and all the counters of the matching keys are deleted.
+    ///
+    /// To never reset unrelated keys, this fails if the key prefix is empty.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn reset_pattern(&self, pattern: &str) -> Result<usize, redis::RedisError> {
+        let prefix = self.prefix();
+        if prefix.is_empty() {
+            return Err(redis::RedisError::from((
+                redis::ErrorKind::ClientError,
+                "reset_pattern requires a non-empty key prefix",
+            )));
+        }
+
+        if let Backend::Memory(store) = &self.inner.backend {
+            return Ok(store.remove(|stored_key| {
+                stored_key
+                    .strip_prefix(prefix.as_ref())
+                    .is_some_and(|key| glob_match(pattern, key))
+            }));
+        }
+
+        let mut con = self.client()?.get_connection()?;
+        if let Algorithm::FixedWindow { .. } = self.inner.algorithm {
+            let scan_pattern = format!("{}{}:*", escape_glob_pattern(&prefix), pattern);
+            let window_keys: Vec<String> = con
+                .scan_match::<_, String>(scan_pattern)?
+                .filter(|window_key| {
+                    strip_window_start(window_key)
+                        .and_then(|base| base.strip_prefix(prefix.as_ref()))
+                        .is_some_and(|key| glob_match(pattern, key))
+                })
+                .collect();
+            if window_keys.is_empty() {
+                return Ok(0);
+            }
+            redis::cmd("DEL").arg(&window_keys).query::<()>(&mut con)?;
+            // Reset the keys themselves too, which drops their local cache entries everywhere.
+            let mut bases: Vec<&str> = window_keys
+                .iter()
+                .filter_map(|window_key| strip_window_start(window_key))
+                .collect();
+            bases.sort_unstable();
+            bases.dedup();
+            for base in &bases {
+                self.reset_raw_with_conn(&mut con, base)?;
+            }
+            return Ok(bases.len());
+        }
+
+        let pattern = format!("{}{}", escape_glob_pattern(&prefix), pattern);
+        let raw_keys: Vec<String> = con.scan_match::<_, String>(pattern)?.collect();
+        let mut count = 0;
+        for raw_key in raw_keys {
+            count += self.reset_raw_with_conn(&mut con, &raw_key)?;
+        }
+        Ok(count)
+    }
+
+    /// Reset the limit for a raw key, which is used verbatim without the key prefix.
+    /// See `allow_raw_n` for the caller's responsibilities.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn reset_raw(&self, raw_key: &str) -> Result<(), redis::RedisError> {
+        if let Backend::Memory(store) = &self.inner.backend {
+            store.remove(|stored_key| stored_key == raw_key);
+            return Ok(());
+        }
+
+        let mut con = self.client()?.get_connection()?;
+        self.reset_raw_with_conn(&mut con, raw_key)?;
+        Ok(())
+    }
+
+    /// Delete a raw key, and return the number of deleted keys.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn reset_raw_with_conn(
+        &self,
+        con: &mut redis::Connection,
+        raw_key: &str,
+    ) -> Result<usize, redis::RedisError> {
+        let deleted: usize = redis::cmd("DEL").arg(raw_key).query(con)?;
+
+        #[cfg(feature = "local_accelerate")]
+        {
+            if let Ok(mut store) = RESET_TIME_STORE.write() {
+                store.remove(raw_key);
+            }
+            let reset_notify = Event::Reset { key: raw_key }.encode();
+            redis::cmd("PUBLISH")
+                .arg(self.inner.event_channel.clone())
+                .arg(&reset_notify)
+                .query::<()>(con)?;
+        }
+
+        Ok(deleted)
+    }
+
+    /// List the keys which currently have an active limit, without the key prefix.
+    /// Keys are collected with `SCAN`, so Redis is never blocked by a `KEYS` call.
+    /// With limit scoped keys, the limit fingerprints are stripped as well.
+    /// The keys are sorted, and each key is listed once,
+    /// even if `SCAN` returns it more than once or it has several limit scoped keys.
+    ///
+    /// For large keyspaces, `keys_iter` streams the keys instead of collecting them.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn keys(&self) -> Result<Vec<String>, redis::RedisError> {
+        let mut keys = self.keys_iter()?.collect::<Result<Vec<_>, _>>()?;
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    /// Iterate over the keys which currently have an active limit, like `keys`,
+    /// fetching them one `SCAN` page at a time so they are never all held in memory.
+    ///
+    /// Like `SCAN`, a key may be yielded more than once,
+    /// and with limit scoped keys once per limit it's checked with.
+    /// With `Algorithm::FixedWindow`, the keys are those of the window counters
+    /// without the window start, yielded once per counter,
+    /// including the counters of past windows which are still retained.
+    /// An error ends the iteration.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn keys_iter(&self) -> Result<KeysIter, redis::RedisError> {
+        let prefix = self.prefix().into_owned();
+        let limit_scoped_keys = self.inner.limit_scoped_keys;
+        match &self.inner.backend {
+            Backend::Redis(client) => {
+                let pattern = format!("{}*", escape_glob_pattern(&prefix));
+                let con = client.get_connection()?;
+                let fixed_window = matches!(self.inner.algorithm, Algorithm::FixedWindow { .. });
+                Ok(KeysIter::new(
+                    con,
+                    pattern,
+                    prefix,
+                    limit_scoped_keys,
+                    fixed_window,
+                ))
+            }
+            Backend::Memory(store) => Ok(KeysIter::from_raw_keys(
+                store.keys(),
+                prefix,
+                limit_scoped_keys,
+            )),
+        }
+    }
+
+    /// Export the state of all the keys, for example to migrate it to another Redis.
+    /// Each entry is a key without the key prefix, and its tat in seconds since the Unix epoch.
+    ///
+    /// Keys are collected with `SCAN` and read in batches with `MGET`.
+    /// This exports the state of the GCRA algorithm, values which are not a tat are skipped,
+    /// and with `Algorithm::FixedWindow` nothing is exported since all the values are counters.
+    /// For large keyspaces, `export_iter` streams the entries instead of collecting them.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn export(&self) -> Result<Vec<(String, f64)>, redis::RedisError> {
+        self.export_iter()?.collect()
+    }
+
+    /// Iterate over the exported state of the keys, like `export`,
+    /// reading one `SCAN` page at a time so the entries are never all held in memory.
+    ///
+    /// Like `SCAN`, a key may be yielded more than once. An error ends the iteration.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn export_iter(&self) -> Result<ExportIter, redis::RedisError> {
+        if let Algorithm::FixedWindow { .. } = self.inner.algorithm {
+            return Ok(ExportIter::from_entries(Vec::new()));
+        }
+        let prefix = self.prefix().into_owned();
+        match &self.inner.backend {
+            Backend::Redis(client) => {
+                let pattern = format!("{}*", escape_glob_pattern(&prefix));
+                let con = client.get_connection()?;
+                Ok(ExportIter::new(con, pattern, prefix, self.script_time()))
+            }
+            Backend::Memory(store) => Ok(ExportIter::from_entries(
+                store
+                    .entries()
+                    .into_iter()
+                    .filter_map(|(key, tat)| Some((key.strip_prefix(&prefix)?.to_string(), tat)))
+                    .collect(),
+            )),
+        }
+    }
+
+    /// Import the state of keys exported by `export`, and return the number of imported keys.
+    ///
+    /// The expiry of each key is recomputed from its tat and the current Redis time,
+    /// and the entries which are already expired are skipped.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn import(
+        &self,
+        entries: impl IntoIterator<Item = (String, f64)>,
+    ) -> Result<usize, redis::RedisError> {
+        let prefix = self.prefix();
+        let client = match &self.inner.backend {
+            Backend::Redis(client) => client,
+            Backend::Memory(store) => {
+                let mut count = 0;
+                for (key, tat) in entries {
+                    count += usize::from(store.insert(format!("{prefix}{key}"), tat));
+                }
+                return Ok(count);
+            }
+        };
+
+        let mut con = client.get_connection()?;
+        let (secs, micros): (u64, u64) = redis::cmd("TIME").query(&mut con)?;
+        let now = secs as f64 + micros as f64 / 1_000_000.0;
+        let (time_epoch, time_scale) = self.script_time();
+        let mut pipe = redis::pipe();
+        let mut count = 0;
+        for (key, tat) in entries {
+            let ttl = tat - now;
+            if ttl <= 0.0 {
+                continue;
+            }
+            let stored_tat = (tat - time_epoch as f64) * time_scale;
+            let stored_tat = if self.inner.microsecond_time {
+                stored_tat.round()
+            } else {
+                stored_tat
+            };
+            pipe.cmd("SET")
+                .arg(format!("{prefix}{key}"))
+                .arg(stored_tat)
+                .arg("EX")
+                .arg(ttl.ceil() as u64)
+                .ignore();
+            count += 1;
+        }
+        if count > 0 {
+            pipe.query::<()>(&mut con)?;
+        }
+        Ok(count)
+    }
+
+    /// Check that a key can be used, with the reason if not.
+    /// Reject the empty keys, which would share one bucket with the key prefix as the key,
+    /// and the keys longer than the max key length.
+    fn validate_key(&self, key: &str) -> Result<(), &'static str> {
+        if key.trim().is_empty() {
+            return Err("the key is empty");
+        }
+        if key.len() > self.inner.max_key_length {
+            return Err("the key is longer than the max key length");
+        }
+        Ok(())
+    }
+
+    /// The key to store the limit in, for the operations which validate the key.
+    fn checked_limit_key(&self, key: &str, limit: &Limit) -> Result<String, RateLimitError> {
+        self.validate_key(key).map_err(RateLimitError::InvalidKey)?;
+        Ok(self.limit_key(key, limit))
+    }
+
+    /// The prefixed key, without limit scoping, for the operations which validate the key.
+    fn checked_raw_key(&self, key: &str) -> Result<String, RateLimitError> {
+        self.validate_key(key).map_err(RateLimitError::InvalidKey)?;
+        Ok(format!("{}{}", self.prefix(), key))
+    }
+
+    fn limit_key(&self, key: &str, limit: &Limit) -> String {
+        if self.inner.limit_scoped_keys {
+            format!(
+                "{}{}{}{}",
+                self.prefix(),
+                key,
+                LIMIT_FINGERPRINT_SEPARATOR,
+                limit.fingerprint()
+            )
+        } else {
+            format!("{}{}", self.prefix(), key)
+        }
+    }
+
+    /// Allow a request to be made within the limit.
+    ///
+    /// The limit can be given owned or borrowed, e.g. `limit`, `&limit` or `&LIMIT` for a `LazyLock`.
+    #[must_use = "a rate-limit decision was computed but not acted on"]
+    pub fn allow(
+        &self,
+        key: &str,
+        limit: impl AsRef<Limit>,
+    ) -> Result<LimitResult, RateLimitError> {
+        self.allow_n(key, limit, 1)
+    }
+
+    /// Allow n requests to be made within the limit.
+    #[must_use = "a rate-limit decision was computed but not acted on"]
+    pub fn allow_n(
+        &self,
+        key: &str,
+        limit: impl AsRef<Limit>,
+        n: usize,
+    ) -> Result<LimitResult, RateLimitError> {
+        self.allow_n_with_policy(key, limit.as_ref(), n, self.inner.failure_mode)
+    }
+
+    /// Allow n requests to be made within the limit,
+    /// handling Redis failures with the given mode instead of the limiter's one.
+    #[must_use = "a rate-limit decision was computed but not acted on"]
+    pub fn allow_n_with_policy(
+        &self,
+        key: &str,
+        limit: &Limit,
+        n: usize,
+        failure_mode: FailureMode,
+    ) -> Result<LimitResult, RateLimitError> {
+        let raw_key = self.checked_limit_key(key, limit)?;
+        let result = self
+            .eval_allow_n(None, &raw_key, limit, n, false)
+            .map(|v| v.result);
+        self.check_plausible(failure_mode.handle(result, limit)?, n)
+    }
+
+    /// Allow n requests to be made within the limit,
+    /// and tell whether the key is first seen by this check.
+    ///
+    /// The check and the first seen flag come from the same script call,
+    /// so they don't race with concurrent requests of the key.
+    /// If a Redis failure is handled by the failure mode, `first_seen` is `false`.
+    #[must_use = "a rate-limit decision was computed but not acted on"]
+    pub fn allow_n_verbose(
+        &self,
+        key: &str,
+        limit: &Limit,
+        n: usize,
+    ) -> Result<VerboseLimitResult, RateLimitError> {
+        let raw_key = self.checked_limit_key(key, limit)?;
+        self.eval_allow_n_verbose(&raw_key, limit, n, false)
+    }
+
+    /// Allow n requests to be made within the limit, with a different burst for this key,
+    /// e.g. a higher one for premium users.
+    ///
+    /// The key and the stored state are the same as with the limit itself,
+    /// even with limit scoped keys, so the burst override can change between calls.
+    /// The limit in the result has the overridden burst.
+    /// Returns `RateLimitError::InvalidLimit` if the burst override is smaller than the rate.
+    #[must_use = "a rate-limit decision was computed but not acted on"]
+    pub fn allow_n_with_burst_override(
+        &self,
+        key: &str,
+        limit: &Limit,
+        n: usize,
+        burst_override: usize,
+    ) -> Result<LimitResult, RateLimitError> {
+        self.allow_n_verbose_with_burst_override(key, limit, n, burst_override)
+            .map(|v| v.result)
+    }
+
+    /// Allow n requests to be made within the limit with a different burst for this key,
+    /// see `allow_n_with_burst_override`, and tell whether the key is first seen.
+    /// The effective limit, with the overridden burst, is in the result.
+    #[must_use = "a rate-limit decision was computed but not acted on"]
+    pub fn allow_n_verbose_with_burst_override(
+        &self,
+        key: &str,
+        limit: &Limit,
+        n: usize,
+        burst_override: usize,
+    ) -> Result<VerboseLimitResult, RateLimitError> {
+        if burst_override < limit.rate {
+            return Err(RateLimitError::InvalidLimit(
+                "the burst override is smaller than the rate",
+            ));
+        }
+        let raw_key = self.checked_limit_key(key, limit)?;
+        let limit = Limit {
+            burst: burst_override,
+            ..limit.clone()
+        };
+        self.eval_allow_n_verbose(&raw_key, &limit, n, false)
+    }
+
+    /// Allow as many of n requests as currently available within the limit,
+    /// instead of all or nothing, and return the number of granted requests with the result.
+    ///
+    /// If no request is granted, the result is limited,
+    /// and `retry_after` tells when a single request can be made.
+    /// With n = 0, nothing is consumed and the result is like `headroom`'s, not limited.
+    #[must_use = "a rate-limit decision was computed but not acted on"]
+    pub fn allow_up_to(
+        &self,
+        key: &str,
+        limit: &Limit,
+        n: usize,
+    ) -> Result<(usize, LimitResult), RateLimitError> {
+        let raw_key = self.checked_limit_key(key, limit)?;
+        let verbose = self.eval_allow_n_verbose(&raw_key, limit, n, true)?;
+        Ok((verbose.granted, verbose.result))
+    }
+
+    fn eval_allow_n_verbose(
+        &self,
+        raw_key: &str,
+        limit: &Limit,
+        n: usize,
+        partial: bool,
+    ) -> Result<VerboseLimitResult, RateLimitError> {
+        let verbose = match self.eval_allow_n(None, raw_key, limit, n, partial) {
+            Ok(verbose) => verbose,
+            Err(e) => {
+                let result = self.inner.failure_mode.handle(Err(e), limit)?;
+                VerboseLimitResult {
+                    granted: if result.limited { 0 } else { n },
+                    result,
+                    first_seen: false,
+                }
+            }
+        };
+        Ok(VerboseLimitResult {
+            result: self.check_plausible(verbose.result, n)?,
+            ..verbose
+        })
+    }
+
+    /// Allow n requests to be made within the limit, on the given connection.
+    /// This is useful to reuse connections which are already held by the caller.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[must_use = "a rate-limit decision was computed but not acted on"]
+    pub fn allow_n_with_conn(
+        &self,
+        con: &mut redis::Connection,
+        key: &str,
+        limit: &Limit,
+        n: usize,
+    ) -> Result<LimitResult, RateLimitError> {
+        let raw_key = self.checked_limit_key(key, limit)?;
+        let result = self
+            .eval_allow_n(Some(con), &raw_key, limit, n, false)
+            .map(|v| v.result);
+        self.check_plausible(self.inner.failure_mode.handle(result, limit)?, n)
+    }
+
+    /// Allow n requests to be made within the limit, without waiting long for Redis.
+    ///
+    /// The connection is made, and the check is run, within the try timeout, 10ms by default.
+    /// If no connection can be made in time, the failure mode applies,
+    /// and with `FailureMode::Error` `RateLimitError::WouldBlock` is returned right away
+    /// if the connection timed out or was refused, or `RateLimitError::Redis` for other errors.
+    #[must_use = "a rate-limit decision was computed but not acted on"]
+    pub fn try_allow_n(
+        &self,
+        key: &str,
+        limit: &Limit,
+        n: usize,
+    ) -> Result<LimitResult, RateLimitError> {
+        let raw_key = self.checked_limit_key(key, limit)?;
+        let result = match &self.inner.backend {
+            #[cfg(not(target_arch = "wasm32"))]
+            Backend::Redis(client) => match self.try_connection(client) {
+                Ok(mut con) => self.eval_allow_n(Some(&mut con), &raw_key, limit, n, false),
+                Err(e)
+                    if self.inner.failure_mode == FailureMode::Error
+                        && (e.is_timeout() || e.is_connection_refusal()) =>
+                {
+                    return Err(RateLimitError::WouldBlock(e));
+                }
+                Err(e) => Err(e),
+            },
+            Backend::Memory(_) => self.eval_allow_n(None, &raw_key, limit, n, false),
+        };
+        let result = self
+            .inner
+            .failure_mode
+            .handle(result.map(|v| v.result), limit)?;
+        self.check_plausible(result, n)
+    }
+
+    /// Open a connection with the try timeout for connecting, reading and writing.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn try_connection(
+        &self,
+        client: &redis::Client,
+    ) -> Result<redis::Connection, redis::RedisError> {
+        let timeout = self.inner.try_timeout;
+        let con = client.get_connection_with_timeout(timeout)?;
+        con.set_read_timeout(Some(timeout))?;
+        con.set_write_timeout(Some(timeout))?;
+        Ok(con)
+    }
+
+    /// Allow n requests to be made within the limit for a raw key.
+    /// The raw key is used verbatim as the Redis key, the key prefix is not added.
+    ///
+    /// This is useful to share keys with other systems,
+    /// but the caller is then responsible for namespacing the keys,
+    /// and for resetting them with `reset_raw` instead of `reset`.
+    #[must_use = "a rate-limit decision was computed but not acted on"]
+    pub fn allow_raw_n(
+        &self,
+        raw_key: &str,
+        limit: &Limit,
+        n: usize,
+    ) -> Result<LimitResult, RateLimitError> {
+        let result = self
+            .eval_allow_n(None, raw_key, limit, n, false)
+            .map(|v| v.result);
+        self.check_plausible(self.inner.failure_mode.handle(result, limit)?, n)
+    }
+
+    /// Allow n requests to be made within the raw GCRA parameters,
+    /// which are given to the script as is instead of being derived from a `Limit`.
+    ///
+    /// The key is prefixed like with `allow_n`, without limit scoping since there is no limit,
+    /// and the check always uses GCRA, whatever the algorithm of the limiter.
+    /// The failure mode applies, but not the plausibility check, which is bounded by the limit.
+    /// Returns `RateLimitError::InvalidLimit` if the emission interval is zero.
+    #[must_use = "a rate-limit decision was computed but not acted on"]
+    pub fn allow_n_with_raw_limit(
+        &self,
+        key: &str,
+        raw_limit: &RawLimit,
+        n: usize,
+    ) -> Result<RawLimitResult, RateLimitError> {
+        if raw_limit.emission_interval.is_zero() {
+            return Err(RateLimitError::InvalidLimit(
+                "the emission interval must be greater than 0",
+            ));
+        }
+        let raw_key = self.checked_raw_key(key)?;
+        let result = self
+            .eval_gcra_n(
+                None,
+                &raw_key,
+                raw_limit.emission_interval.as_secs_f64(),
+                raw_limit.burst_offset.as_secs_f64(),
+                n,
+                false,
+            )
+            .map(|(result, _, _)| RawLimitResult {
+                retry_after: self.jitter(result.retry_after),
+                ..result
+            });
+        if !self.inner.dry_run {
+            self.inner
+                .stats
+                .record(result.as_ref().map(|result| result.limited));
+        }
+        Ok(self
+            .inner
+            .failure_mode
+            .handle_raw(result, raw_limit.emission_interval)?)
+    }
+
+    /// Reject a result with a duration longer than both the maximum plausible duration
+    /// and the longest duration the limit can produce for n requests,
+    /// which means the Redis clock is off or the stored state is corrupted.
+    fn check_plausible(
+        &self,
+        result: LimitResult,
+        n: usize,
+    ) -> Result<LimitResult, RateLimitError> {
+        let emission_interval = result.limit.period_seconds as f64 / result.limit.rate as f64;
+        let limit_max = time::Duration::try_from_secs_f64(
+            (result.limit.burst as f64 + n as f64) * emission_interval,
+        )
+        .unwrap_or(time::Duration::MAX);
+        let max = self.inner.max_plausible_duration.max(limit_max);
+        if result.reset_after > max || result.retry_after.is_some_and(|d| d > max) {
+            return Err(RateLimitError::ImplausibleResult(result));
+        }
+        Ok(result)
+    }
+
+    /// Check n requests, or as many as available if `partial`.
+    fn eval_allow_n(
+        &self,
+        con: Option<&mut Connection>,
+        raw_key: &str,
+        limit: &Limit,
+        n: usize,
+        partial: bool,
+    ) -> Result<VerboseLimitResult, RedisError> {
+        let verbose = match self.inner.algorithm {
+            #[cfg(not(target_arch = "wasm32"))]
+            Algorithm::FixedWindow { retention } => {
+                self.eval_fixed_window_n(con, raw_key, limit, n, partial, retention)
+            }
+            Algorithm::Gcra => {
+                let emission_interval = limit.period_seconds as f64 / limit.rate as f64;
+                let brust_offset = limit.burst as f64 * emission_interval;
+                self.eval_gcra_n(con, raw_key, emission_interval, brust_offset, n, partial)
+            }
+        };
+        let mut verbose = verbose.map(|(result, first_seen, granted)| VerboseLimitResult {
+            result: LimitResult::from_raw(result, limit),
+            first_seen,
+            granted,
+        });
+        if !self.inner.dry_run {
+            self.inner
+                .stats
+                .record(verbose.as_ref().map(|v| v.result.limited));
+        }
+        if let Ok(verbose) = &mut verbose {
+            verbose.result.retry_after = self.jitter(verbose.result.retry_after);
+        }
+        verbose
+    }
+
+    /// Check n requests with the GCRA parameters in seconds, or as many as available if `partial`,
+    /// and tell whether the key is first seen and how many requests are granted.
+    fn eval_gcra_n(
+        &self,
+        con: Option<&mut Connection>,
+        raw_key: &str,
+        emission_interval: f64,
+        brust_offset: f64,
+        n: usize,
+        partial: bool,
+    ) -> Result<(RawLimitResult, bool, usize), RedisError> {
+        if self.inner.dry_run {
+            let peek_n = Self::dry_run_peek_n(n, partial);
+            let result =
+                self.eval_gcra_peek_n(con, raw_key, emission_interval, brust_offset, peek_n)?;
+            return Ok(self.dry_run_result(result, n));
+        }
+
+        match &self.inner.backend {
+            Backend::Memory(store) => {
+                let (decision, first_seen, granted) =
+                    store.decide(raw_key, emission_interval, brust_offset, n, partial, true);
+                Ok((RawLimitResult::from_decision(decision), first_seen, granted))
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            Backend::Redis(_) => {
+                self.eval_gcra_n_in_redis(con, raw_key, emission_interval, brust_offset, n, partial)
+            }
+        }
+    }
+
+    /// Check n requests with the GCRA parameters in seconds in Redis, see `eval_gcra_n`.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn eval_gcra_n_in_redis(
+        &self,
+        con: Option<&mut redis::Connection>,
+        raw_key: &str,
+        emission_interval: f64,
+        brust_offset: f64,
+        n: usize,
+        partial: bool,
+    ) -> Result<(RawLimitResult, bool, usize), redis::RedisError> {
+        let tat_increment = emission_interval * n as f64;
+
+        #[cfg(feature = "local_accelerate")]
+        let now = time::Instant::now();
+        #[cfg(feature = "local_accelerate")]
+        // The cached reset time only tells if all the n requests are limited.
+        if let Ok(store) = RESET_TIME_STORE.try_read()
+            && !partial
+            && let Some(entry) = store.get(raw_key)
+            && now.duration_since(entry.cached_at) <= self.inner.local_cache_ttl
+        {
+            let reset_time = entry.reset_time;
+            let reset_after = reset_time.duration_since(now).as_secs_f64();
+            let diff: f64 = reset_after + tat_increment - brust_offset;
+            if diff > 0.0 {
+                // The cached reset time may be stale and beyond the burst,
+                // so clamp `remaining` to 0 like the Redis path does.
+                let remaining = f64::floor((brust_offset - reset_after) / emission_interval);
+                StatsCounters::increment(&self.inner.stats.local_hits);
+                let result = RawLimitResult {
+                    limited: true,
+                    remaining: remaining.max(0.0) as usize,
+                    retry_after: Some(time::Duration::from_secs_f64(diff.abs())),
+                    reset_after: reset_time.duration_since(now),
+                };
+                return Ok((result, false, 0));
+            }
+        }
+
+        let mut owned_con;
+        let con = match con {
+            Some(con) => con,
+            None => {
+                owned_con = self.client()?.get_connection()?;
+                &mut owned_con
+            }
+        };
+        let (time_epoch, time_scale) = self.script_time();
+        let mut args = redis::Cmd::new();
+        args.arg(self.to_script_time(emission_interval))
+            .arg(self.to_script_time(brust_offset))
+            .arg(self.to_script_time(tat_increment))
+            .arg(n)
+            .arg(time_epoch)
+            .arg(time_scale)
+            .arg(self.inner.ttl_rounding.as_str())
+            .arg(partial);
+        StatsCounters::increment(&self.inner.stats.redis_calls);
+        let result: redis::Value =
+            self.invoke_gcra(con, &ALLOW_N_SCRIPT, ALLOW_N_FUNCTION, raw_key, &args)?;
+        let (result, first_seen, granted) = RawLimitResult::from_verbose_script_reply(&result)?;
+        self.sync_allowed(con, raw_key, &result, true)?;
+
+        #[cfg(feature = "local_accelerate")]
+        cache_reset_time(raw_key, now, result.reset_after);
+
+        Ok((result, first_seen, granted))
+    }
+
+    /// After an allowed check, wait for the replicas if `set_wait_replicas` is set,
+    /// and publish a consume event if `publish` and `set_publish_consume_events` are set.
+    #[cfg_attr(not(feature = "local_accelerate"), allow(unused_variables))]
+    #[cfg(not(target_arch = "wasm32"))]
+    fn sync_allowed(
+        &self,
+        con: &mut redis::Connection,
+        raw_key: &str,
+        result: &RawLimitResult,
+        publish: bool,
+    ) -> Result<(), redis::RedisError> {
+        if result.limited {
+            return Ok(());
+        }
+
+        if let Some((replicas, timeout)) = self.inner.wait_replicas {
+            redis::cmd("WAIT")
+                .arg(replicas)
+                .arg(timeout.as_millis() as u64)
+                .query::<usize>(con)?;
+        }
+
+        #[cfg(feature = "local_accelerate")]
+        if publish && self.inner.publish_consume_events {
+            let consume_notify = Event::Consume {
+                key: raw_key,
+                reset_after_secs: result.reset_after.as_secs_f64(),
+            }
+            .encode();
+            redis::cmd("PUBLISH")
+                .arg(&self.inner.event_channel)
+                .arg(&consume_notify)
+                .query::<()>(con)?;
+        }
+
+        Ok(())
+    }
+
+    /// Run a GCRA script on the key with the arguments collected in `args`,
+    /// or call the same function with `FCALL` if `set_use_functions` is enabled.
+    ///
+    /// `Script::invoke` tries `EVALSHA` first, and loads the script again on `NOSCRIPT`,
+    /// so a Redis restart or `SCRIPT FLUSH` is never surfaced to the caller.
+    /// Keep this fallback if the invocation is changed, `test_noscript_recovery` covers it.
+    #[cfg_attr(not(feature = "functions"), allow(unused_variables))]
+    #[cfg(not(target_arch = "wasm32"))]
+    fn invoke_gcra<T: redis::FromRedisValue>(
+        &self,
+        con: &mut redis::Connection,
+        script: &redis::Script,
+        function: &str,
+        key: &str,
+        args: &redis::Cmd,
+    ) -> Result<T, redis::RedisError> {
+        let args: Vec<&[u8]> = args
+            .args_iter()
+            .filter_map(|arg| match arg {
+                redis::Arg::Simple(arg) => Some(arg),
+                redis::Arg::Cursor => None,
+            })
+            .collect();
+
+        #[cfg(feature = "functions")]
+        if self.inner.use_functions {
+            return redis::cmd("FCALL")
+                .arg(function)
+                .arg(1)
+                .arg(key)
+                .arg(args)
+                .query(con);
+        }
+
+        script.key(key).arg(args).invoke(con)
+    }
+
+    /// Check n requests in the fixed window of the key, or as many as available if `partial`,
+    /// and tell whether the window is first seen and how many requests are granted.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn eval_fixed_window_n(
+        &self,
+        con: Option<&mut redis::Connection>,
+        raw_key: &str,
+        limit: &Limit,
+        n: usize,
+        partial: bool,
+        retention: time::Duration,
+    ) -> Result<(RawLimitResult, bool, usize), redis::RedisError> {
+        if self.inner.dry_run {
+            let peek_n = Self::dry_run_peek_n(n, partial);
+            let (result, _, _) =
+                self.invoke_fixed_window(con, raw_key, limit, peek_n, false, retention, true)?;
+            return Ok(self.dry_run_result(result, n));
+        }
+
+        #[cfg(feature = "local_accelerate")]
+        let now = time::Instant::now();
+        #[cfg(feature = "local_accelerate")]
+        // Only full windows are cached, so the key is limited until the cached window end.
+        if n > 0
+            && let Ok(store) = RESET_TIME_STORE.try_read()
+            && let Some(entry) = store.get(raw_key)
+            && now.duration_since(entry.cached_at) <= self.inner.local_cache_ttl
+            && entry.reset_time > now
+        {
+            StatsCounters::increment(&self.inner.stats.local_hits);
+            let reset_after = entry.reset_time.duration_since(now);
+            let result = RawLimitResult {
+                limited: true,
+                remaining: 0,
+                retry_after: Some(reset_after),
+                reset_after,
+            };
+            return Ok((result, false, 0));
+        }
+
+        let mut owned_con;
+        let con = match con {
+            Some(con) => con,
+            None => {
+                owned_con = self.client()?.get_connection()?;
+                &mut owned_con
+            }
+        };
+        StatsCounters::increment(&self.inner.stats.redis_calls);
+        let (result, first_seen, granted) = self.invoke_fixed_window(
+            Some(&mut *con),
+            raw_key,
+            limit,
+            n,
+            partial,
+            retention,
+            false,
+        )?;
+        let window_full = result.limited || result.remaining == 0;
+        self.sync_allowed(con, raw_key, &result, window_full)?;
+
+        #[cfg(feature = "local_accelerate")]
+        if window_full {
+            cache_reset_time(raw_key, now, result.reset_after);
+        }
+
+        Ok((result, first_seen, granted))
+    }
+
+    /// Run the fixed window script, which only reads the counter if `peek`.
+    #[allow(clippy::too_many_arguments)]
+    #[cfg(not(target_arch = "wasm32"))]
+    fn invoke_fixed_window(
+        &self,
+        con: Option<&mut redis::Connection>,
+        raw_key: &str,
+        limit: &Limit,
+        n: usize,
+        partial: bool,
+        retention: time::Duration,
+        peek: bool,
+    ) -> Result<(RawLimitResult, bool, usize), redis::RedisError> {
+        let mut owned_con;
+        let con = match con {
+            Some(con) => con,
+            None => {
+                owned_con = self.client()?.get_connection()?;
+                &mut owned_con
+            }
+        };
+        let result: redis::Value = FIXED_WINDOW_SCRIPT
+            .key(raw_key)
+            .arg(limit.period_seconds)
+            .arg(limit.rate)
+            .arg(n)
+            .arg(retention.as_secs())
+            .arg(partial)
+            .arg(peek)
+            .invoke(con)?;
+        RawLimitResult::from_verbose_script_reply(&result)
+    }
+
+    /// The number of requests to peek in dry run,
+    /// a partial check would only be limited if not even one request is available.
+    fn dry_run_peek_n(n: usize, partial: bool) -> usize {
+        if partial { n.min(1) } else { n }
+    }
+
+    /// The result of a check in dry run, from the result of peeking the n requests:
+    /// a would-be denial is counted, and all the requests are reported as granted.
+    /// This is the only place where dry run changes a result,
+    /// the consuming checks all peek instead of consuming in dry run and end up here.
+    fn dry_run_result(&self, result: RawLimitResult, n: usize) -> (RawLimitResult, bool, usize) {
+        if result.limited {
+            self.inner.dry_run_denials.fetch_add(1, Ordering::Relaxed);
+        }
+        let result = RawLimitResult {
+            limited: false,
+            ..result
+        };
+        (result, false, n)
+    }
+
+    /// Get the count of requests of a key for the limit in the fixed window
+    /// containing `window_start`, see `Algorithm::FixedWindow`.
+    /// The window start is rounded down to a multiple of `period_seconds` since the Unix epoch.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn window_count(
+        &self,
+        key: &str,
+        limit: &Limit,
+        window_start: time::SystemTime,
+    ) -> Result<usize, RateLimitError> {
+        let window_start = window_start
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let period_seconds = limit.period_seconds as u64;
+        let window_key = format!(
+            "{}:{}",
+            self.checked_limit_key(key, limit)?,
+            window_start - window_start % period_seconds
+        );
+        let mut con = self.client()?.get_connection()?;
+        let count: Option<usize> = redis::cmd("GET").arg(window_key).query(&mut con)?;
+        Ok(count.unwrap_or(0))
+    }
+
+    /// Check whether a request would be allowed within the limit, without consuming it.
+    pub fn peek(&self, key: &str, limit: impl AsRef<Limit>) -> Result<LimitResult, RateLimitError> {
+        let limit = limit.as_ref();
+        Ok(self.eval_peek_n(&self.checked_limit_key(key, limit)?, limit, 1)?)
+    }
+
+    /// Get how many requests would be allowed within the limit at this instant,
+    /// without consuming them.
+    pub fn headroom(&self, key: &str, limit: &Limit) -> Result<usize, RateLimitError> {
+        let result = self.eval_peek_n(&self.checked_limit_key(key, limit)?, limit, 0)?;
+        Ok(result.remaining)
+    }
+
+    /// Check n requests with the algorithm of the limiter, without consuming them.
+    fn eval_peek_n(
+        &self,
+        raw_key: &str,
+        limit: &Limit,
+        n: usize,
+    ) -> Result<LimitResult, RedisError> {
+        let result = match self.inner.algorithm {
+            #[cfg(not(target_arch = "wasm32"))]
+            Algorithm::FixedWindow { retention } => self
+                .invoke_fixed_window(None, raw_key, limit, n, false, retention, true)
+                .map(|(result, _, _)| result),
+            Algorithm::Gcra => {
+                let emission_interval = limit.period_seconds as f64 / limit.rate as f64;
+                let brust_offset = limit.burst as f64 * emission_interval;
+                self.eval_gcra_peek_n(None, raw_key, emission_interval, brust_offset, n)
+            }
+        };
+        Ok(LimitResult::from_raw(result?, limit))
+    }
+
+    /// Check n requests with the GCRA parameters in seconds, without consuming them.
+    #[cfg_attr(target_arch = "wasm32", allow(unused_variables))]
+    fn eval_gcra_peek_n(
+        &self,
+        con: Option<&mut Connection>,
+        raw_key: &str,
+        emission_interval: f64,
+        brust_offset: f64,
+        n: usize,
+    ) -> Result<RawLimitResult, RedisError> {
+        match &self.inner.backend {
+            Backend::Memory(store) => {
+                let (decision, _, _) =
+                    store.decide(raw_key, emission_interval, brust_offset, n, false, false);
+                Ok(RawLimitResult::from_decision(decision))
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            Backend::Redis(_) => {
+                self.eval_gcra_peek_n_in_redis(con, raw_key, emission_interval, brust_offset, n)
+            }
+        }
+    }
+
+    /// Check n requests with the GCRA parameters in seconds in Redis, see `eval_gcra_peek_n`.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn eval_gcra_peek_n_in_redis(
+        &self,
+        con: Option<&mut redis::Connection>,
+        raw_key: &str,
+        emission_interval: f64,
+        brust_offset: f64,
+        n: usize,
+    ) -> Result<RawLimitResult, redis::RedisError> {
+        let tat_increment = emission_interval * n as f64;
+        let mut owned_con;
+        let con = match con {
+            Some(con) => con,
+            None => {
+                owned_con = self.client()?.get_connection()?;
+                &mut owned_con
+            }
+        };
+        let (time_epoch, time_scale) = self.script_time();
+        let mut args = redis::Cmd::new();
+        args.arg(self.to_script_time(emission_interval))
+            .arg(self.to_script_time(brust_offset))
+            .arg(self.to_script_time(tat_increment))
+            .arg(time_epoch)
+            .arg(time_scale);
+        let result: redis::Value =
+            self.invoke_gcra(con, &PEEK_N_SCRIPT, PEEK_N_FUNCTION, raw_key, &args)?;
+        RawLimitResult::from_script_reply(&result)
+    }
+
+    /// Allow a request to be made within the default limit.
+    #[must_use = "a rate-limit decision was computed but not acted on"]
+    pub fn allow_default(&self, key: &str) -> Result<LimitResult, RateLimitError> {
+        self.allow_n_default(key, 1)
+    }
+
+    /// Allow n requests to be made within the default limit.
+    /// Returns `RateLimitError::MissingDefaultLimit` if no default limit is set.
+    #[must_use = "a rate-limit decision was computed but not acted on"]
+    pub fn allow_n_default(&self, key: &str, n: usize) -> Result<LimitResult, RateLimitError> {
+        let limit = self
+            .inner
+            .default_limit
+            .as_ref()
+            .ok_or(RateLimitError::MissingDefaultLimit)?;
+        self.allow_n(key, limit, n)
+    }
+
+    /// Give back a request which was allowed within the limit.
+    pub fn refund(&self, key: &str, limit: &Limit) -> Result<(), RateLimitError> {
+        self.refund_n(key, limit, 1)
+    }
+
+    /// Give back n requests which were allowed within the limit.
+    /// The refunded quota will never exceed the burst of the limit.
+    ///
+    /// With `Algorithm::FixedWindow`, the counter of the current window is decremented,
+    /// so requests counted in a previous window can't be given back.
+    pub fn refund_n(&self, key: &str, limit: &Limit, n: usize) -> Result<(), RateLimitError> {
+        let key = self.checked_limit_key(key, limit)?;
+        let emission_interval = limit.period_seconds as f64 / limit.rate as f64;
+        let tat_decrement = emission_interval * n as f64;
+
+        match (&self.inner.backend, self.inner.algorithm) {
+            #[cfg(not(target_arch = "wasm32"))]
+            (_, Algorithm::FixedWindow { .. }) => {
+                let mut con = self.client()?.get_connection()?;
+                FIXED_WINDOW_REFUND_SCRIPT
+                    .key(&key)
+                    .arg(limit.period_seconds)
+                    .arg(n)
+                    .invoke::<()>(&mut con)?;
+            }
+            (Backend::Memory(store), Algorithm::Gcra) => store.refund(&key, tat_decrement),
+            #[cfg(not(target_arch = "wasm32"))]
+            (Backend::Redis(client), Algorithm::Gcra) => {
+                let mut con = client.get_connection()?;
+                let (time_epoch, time_scale) = self.script_time();
+                let mut args = redis::Cmd::new();
+                args.arg(self.to_script_time(tat_decrement))
+                    .arg(time_epoch)
+                    .arg(time_scale)
+                    .arg(self.inner.ttl_rounding.as_str());
+                self.invoke_gcra::<()>(&mut con, &REFUND_N_SCRIPT, REFUND_N_FUNCTION, &key, &args)?;
+            }
+        }
+
+        // The cached reset time is too pessimistic after a refund,
+        // drop it so the next call asks Redis.
         #[cfg(feature = "local_accelerate")]
         if let Ok(mut store) = RESET_TIME_STORE.try_write() {
-            store.insert(key, now + reset_after);
+            store.remove(&key);
+        }
+
+        Ok(())
+    }
+
+    /// Give back capacity to a key short of a full reset, e.g. by support staff,
+    /// and return the new number of remaining requests.
+    ///
+    /// This moves the tat backward by `units` emission intervals like `refund_n`,
+    /// so the restored capacity never exceeds the burst of the limit.
+    /// The remaining requests are read after the grant, so concurrent requests may be included.
+    pub fn grant(&self, key: &str, limit: &Limit, units: usize) -> Result<usize, RateLimitError> {
+        self.refund_n(key, limit, units)?;
+        self.headroom(key, limit)
+    }
+
+    /// Allow a request to be made within the limit,
+    /// or return `RateLimitError::Limited` with the result if it's limited,
+    /// so handlers can branch on the error alone:
+    /// limited with 429, `RateLimitError::is_unavailable` with 503, and the rest with 500.
+    #[must_use = "a rate-limit decision was computed but not acted on"]
+    pub fn allow_or_error(
+        &self,
+        key: &str,
+        limit: impl AsRef<Limit>,
+    ) -> Result<LimitResult, RateLimitError> {
+        let result = self.allow(key, limit)?;
+        if result.limited {
+            return Err(RateLimitError::Limited(result));
+        }
+        Ok(result)
+    }
+
+    /// Consume one request within the limit and run `f`.
+    /// If `f` returns an error, the request is refunded.
+    /// If the request is limited, `f` is not run and `RateLimitError::Limited` is returned.
+    ///
+    /// The refund is best-effort, if it fails the request stays consumed
+    /// and the result of `f` is still returned.
+    pub fn with_permit<T, E>(
+        &self,
+        key: &str,
+        limit: &Limit,
+        f: impl FnOnce() -> Result<T, E>,
+    ) -> Result<Result<T, E>, RateLimitError> {
+        let result = self.allow(key, limit)?;
+        if result.limited {
+            return Err(RateLimitError::Limited(result));
+        }
+
+        let output = f();
+        if output.is_err() {
+            let _ = self.rollback_n(key, limit, 1);
+        }
+        Ok(output)
+    }
+
+    /// Give back n requests consumed by a check which is rolled back.
+    /// Nothing is given back in dry run, since the check consumed nothing.
+    fn rollback_n(&self, key: &str, limit: &Limit, n: usize) -> Result<(), RateLimitError> {
+        if self.inner.dry_run {
+            return Ok(());
+        }
+        self.refund_n(key, limit, n)
+    }
+
+    /// Run `f` with a session, which checks limits on one connection held for the whole closure.
+    /// This avoids reconnecting on every check in tight loops, e.g. when draining a queue.
+    ///
+    /// The connection is opened on the first check, and reopened if it is dropped.
+    pub fn session<T, E>(&self, f: impl FnOnce(&mut Session<'_>) -> Result<T, E>) -> Result<T, E> {
+        let mut session = Session {
+            limiter: self,
+            #[cfg(not(target_arch = "wasm32"))]
+            con: None,
+        };
+        f(&mut session)
+    }
+}
+
+/// Limit checks sharing one connection, created by `Limiter::session`.
+pub struct Session<'a> {
+    limiter: &'a Limiter,
+    #[cfg(not(target_arch = "wasm32"))]
+    con: Option<redis::Connection>,
+}
+
+impl Session<'_> {
+    /// Allow a request to be made within the limit.
+    #[must_use = "a rate-limit decision was computed but not acted on"]
+    pub fn allow(&mut self, key: &str, limit: &Limit) -> Result<LimitResult, RateLimitError> {
+        self.allow_n(key, limit, 1)
+    }
+
+    /// Allow n requests to be made within the limit.
+    #[must_use = "a rate-limit decision was computed but not acted on"]
+    pub fn allow_n(
+        &mut self,
+        key: &str,
+        limit: &Limit,
+        n: usize,
+    ) -> Result<LimitResult, RateLimitError> {
+        let limiter = self.limiter;
+        let raw_key = limiter.checked_limit_key(key, limit)?;
+        let result = self
+            .connection()
+            .and_then(|con| limiter.eval_allow_n(con, &raw_key, limit, n, false));
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Err(e) = &result
+            && (e.is_connection_dropped() || e.is_io_error())
+        {
+            self.con = None;
+        }
+        let result = limiter
+            .inner
+            .failure_mode
+            .handle(result.map(|v| v.result), limit)?;
+        limiter.check_plausible(result, n)
+    }
+
+    /// Get the held connection, opening it if needed.
+    /// The in-memory limiter has no connection.
+    fn connection(&mut self) -> Result<Option<&mut Connection>, RedisError> {
+        match &self.limiter.inner.backend {
+            Backend::Memory(_) => Ok(None),
+            #[cfg(not(target_arch = "wasm32"))]
+            Backend::Redis(client) => {
+                if self.con.is_none() {
+                    self.con = Some(client.get_connection()?);
+                }
+                Ok(self.con.as_mut())
+            }
+        }
+    }
+}
+
+/// Strip the window start from the key of a fixed window counter, `{key}:{window start}`.
+fn strip_window_start(window_key: &str) -> Option<&str> {
+    let (key, window_start) = window_key.rsplit_once(':')?;
+    if window_start.is_empty() || !window_start.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(key)
+}
+
+/// Match a string against a Redis glob pattern,
+/// supporting `*`, `?`, `[...]` classes with ranges and `^` negation, and `\` escapes.
+fn glob_match(pattern: &str, s: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+    glob_match_chars(&pattern, &s)
+}
+
+fn glob_match_chars(pattern: &[char], s: &[char]) -> bool {
+    let Some((&p, rest)) = pattern.split_first() else {
+        return s.is_empty();
+    };
+    match p {
+        '*' => (0..=s.len()).any(|i| glob_match_chars(rest, &s[i..])),
+        '?' => !s.is_empty() && glob_match_chars(rest, &s[1..]),
+        '[' => {
+            let Some((&c, s_rest)) = s.split_first() else {
+                return false;
+            };
+            let (negate, mut class) = match rest.split_first() {
+                Some(('^', class)) => (true, class),
+                _ => (false, rest),
+            };
+            let mut matched = false;
+            while let Some((&first, tail)) = class.split_first() {
+                if first == ']' {
+                    break;
+                }
+                let (first, tail) = match (first, tail.split_first()) {
+                    ('\\', Some((&escaped, tail))) => (escaped, tail),
+                    _ => (first, tail),
+                };
+                class = match tail {
+                    ['-', last, tail @ ..] if *last != ']' => {
+                        let (lo, hi) = (first.min(*last), first.max(*last));
+                        matched |= (lo..=hi).contains(&c);
+                        tail
+                    }
+                    _ => {
+                        matched |= first == c;
+                        tail
+                    }
+                };
+            }
+            // Skip the closing bracket, an unclosed class runs to the end of the pattern.
+            let rest = class.get(1..).unwrap_or_default();
+            matched != negate && glob_match_chars(rest, s_rest)
+        }
+        '\\' if !rest.is_empty() => {
+            s.first() == Some(&rest[0]) && glob_match_chars(&rest[1..], &s[1..])
         }
+        _ => s.first() == Some(&p) && glob_match_chars(rest, &s[1..]),
+    }
+}
 
-        Ok(LimitResult {
-            limited,
-            remaining,
-            retry_after,
-            reset_after,
-        })
+/// Escape the glob special characters of a Redis `MATCH` pattern.
+fn escape_glob_pattern(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
     }
+    escaped
 }
 
 #[test]
@@ -239,23 +2746,999 @@ fn test_limiter() {
     }
 
     let result = limiter.allow_n(key, &limit, 4).unwrap();
-    assert_eq!(result.limited, false);
+    assert!(!result.limited);
     assert_eq!(result.remaining, 1);
     let result = limiter.allow_n(key, &limit, 3).unwrap();
-    assert_eq!(result.limited, true);
+    assert!(result.limited);
     assert_eq!(result.remaining, 1);
-    let result = limiter.allow(&key, &limit).unwrap();
-    assert_eq!(result.limited, false);
+    let result = limiter.allow(key, &limit).unwrap();
+    assert!(!result.limited);
     assert_eq!(result.remaining, 0);
 
     let result = limiter.allow_n(key, &limit, 5).unwrap();
-    assert_eq!(result.limited, true);
+    assert!(result.limited);
     limiter.reset(key).unwrap();
 
     #[cfg(feature = "local_accelerate")]
-    // Wait for the reset event to be processed in the other thread
-    thread::sleep(time::Duration::from_millis(100));
+    {
+        // Wait for the reset event to be processed in the other thread
+        thread::sleep(time::Duration::from_millis(100));
+        assert!(limiter.reset_events_processed() > 0);
+        assert!(limiter.last_reset_event_at().is_some());
+    }
+
+    let result = limiter.allow_n(key, &limit, 5).unwrap();
+    assert!(!result.limited);
+}
+
+#[test]
+fn test_new_limit_named() {
+    let limit = new_limit!(period_seconds: 10, rate: 1, burst: 5);
+    assert_eq!(limit.rate, 1);
+    assert_eq!(limit.burst, 5);
+    assert_eq!(limit.period_seconds, 10);
+
+    let limit = new_limit!(rate: 2, burst: 3, period_seconds: 4,);
+    assert_eq!((limit.rate, limit.burst, limit.period_seconds), (2, 3, 4));
+}
+
+#[test]
+fn test_limit_try_new() {
+    assert_eq!(Limit::try_new(1, 5, 10).unwrap(), Limit::new(1, 5, 10));
+    for (rate, burst, period_seconds) in [(1, 5, 0), (0, 5, 10), (6, 5, 10)] {
+        assert!(matches!(
+            Limit::try_new(rate, burst, period_seconds),
+            Err(RateLimitError::InvalidLimit(_))
+        ));
+    }
+    assert_eq!(Limit::checked_new(0, 0, 0), Limit::new(1, 1, 1));
+    assert_eq!(Limit::checked_new(6, 5, 10), Limit::new(6, 6, 10));
+    assert_eq!(Limit::checked_new(1, 5, 10), Limit::new(1, 5, 10));
+}
+
+#[test]
+fn test_limit_with_extra_burst() {
+    assert_eq!(Limit::with_extra_burst(5, 10, 0), Limit::new(5, 5, 10));
+    assert_eq!(Limit::with_extra_burst(5, 10, 3), Limit::new(5, 8, 10));
+}
+
+#[test]
+fn test_emission_interval() {
+    assert_eq!(
+        Limit::new(4, 4, 1).emission_interval(),
+        time::Duration::from_millis(250)
+    );
+    let result = Limiter::in_memory()
+        .allow("test_emission_interval", Limit::new(1, 5, 10))
+        .unwrap();
+    assert_eq!(result.emission_interval(), time::Duration::from_secs(10));
+    assert_eq!(
+        Limit::new(1, 1, usize::MAX).emission_interval(),
+        time::Duration::MAX
+    );
+}
+
+#[test]
+fn test_limit_simulate() {
+    // 1 request per second with a burst of 3, at 2 requests per second for 3 seconds:
+    // the burst and the 2 requests replenished by then are allowed.
+    let limit = Limit::new(1, 3, 1);
+    let allowed = limit.simulate((0..6).map(|i| i as f64 * 0.5));
+    assert_eq!(allowed, [true, true, true, true, true, false]);
+}
+
+#[test]
+fn test_limit_eq_hash() {
+    use std::collections::HashSet;
+
+    let limits: HashSet<Limit> = [
+        Limit::new(1, 5, 10),
+        new_limit!(period_seconds: 10, rate: 1, burst: 5),
+        Limit::new(2, 5, 10),
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(limits.len(), 2);
+    assert!(limits.contains(&Limit::new(2, 5, 10)));
+}
+
+#[test]
+fn test_invalid_key() {
+    let limiter = Limiter::in_memory().set_max_key_length(8);
+    let limit = Limit::new(1, 5, 10);
+    for key in ["", "  "] {
+        assert!(matches!(
+            limiter.allow(key, &limit),
+            Err(RateLimitError::InvalidKey("the key is empty"))
+        ));
+        assert!(matches!(
+            limiter.reset(key),
+            Err(RateLimitError::InvalidKey("the key is empty"))
+        ));
+        assert!(matches!(
+            limiter.peek(key, &limit),
+            Err(RateLimitError::InvalidKey(_))
+        ));
+        assert!(matches!(
+            limiter.headroom(key, &limit),
+            Err(RateLimitError::InvalidKey(_))
+        ));
+        assert!(matches!(
+            limiter.refund_n(key, &limit, 1),
+            Err(RateLimitError::InvalidKey(_))
+        ));
+        assert!(matches!(
+            limiter.grant(key, &limit, 1),
+            Err(RateLimitError::InvalidKey(_))
+        ));
+        assert!(matches!(
+            limiter.window_count(key, &limit, time::SystemTime::now()),
+            Err(RateLimitError::InvalidKey(_))
+        ));
+    }
+    assert!(matches!(
+        limiter.allow_n("123456789", &limit, 1),
+        Err(RateLimitError::InvalidKey(_))
+    ));
+    assert!(limiter.allow_up_to("123456789", &limit, 1).is_err());
+    assert!(limiter.allow("12345678", &limit).is_ok());
+    assert!(limiter.reset("12345678").is_ok());
+}
+
+#[test]
+fn test_allow_or_error() {
+    let limiter = Limiter::in_memory();
+    let limit = Limit::new(1, 1, 10);
+    assert!(
+        limiter
+            .allow_or_error("test_allow_or_error", &limit)
+            .is_ok()
+    );
+    let err = limiter
+        .allow_or_error("test_allow_or_error", &limit)
+        .unwrap_err();
+    assert!(matches!(err, RateLimitError::Limited(_)));
+    assert!(!err.is_unavailable());
+
+    let limiter = Limiter::new(redis::Client::open("redis://127.0.0.1:1/").unwrap());
+    let err = limiter
+        .allow_or_error("test_allow_or_error", &limit)
+        .unwrap_err();
+    assert!(err.is_unavailable());
+}
+
+#[test]
+fn test_burst_override() {
+    let limiter = Limiter::in_memory().set_limit_scoped_keys(true);
+    let limit = Limit::new(1, 2, 10);
+    let key = "test_burst_override";
+    let result = limiter
+        .allow_n_with_burst_override(key, &limit, 4, 5)
+        .unwrap();
+    assert!(!result.limited);
+    assert_eq!(result.remaining, 1);
+    assert_eq!(result.limit, Limit::new(1, 5, 10));
+    // The stored state is shared with the limit itself.
+    assert!(limiter.allow(key, &limit).unwrap().limited);
+    assert!(matches!(
+        limiter.allow_n_with_burst_override(key, &Limit::new(2, 2, 10), 1, 1),
+        Err(RateLimitError::InvalidLimit(_))
+    ));
+
+    let verbose = limiter
+        .allow_n_verbose_with_burst_override("test_burst_override_verbose", &limit, 1, 3)
+        .unwrap();
+    assert!(verbose.first_seen);
+    assert_eq!(verbose.effective_limit(), &Limit::new(1, 3, 10));
+    let verbose = limiter
+        .allow_n_verbose("test_burst_override_verbose", &limit, 1)
+        .unwrap();
+    assert_eq!(verbose.effective_limit(), &limit);
+}
+
+#[test]
+fn test_health_check() {
+    assert!(Limiter::in_memory().health_check().is_ok());
+    let limiter = Limiter::new(redis::Client::open("redis://127.0.0.1:1/").unwrap());
+    assert!(limiter.health_check().unwrap_err().is_unavailable());
+}
+
+#[test]
+fn test_raw_limit() {
+    let limit = Limit::new(2, 4, 10);
+    let raw_limit = RawLimit::from(&limit);
+    assert_eq!(raw_limit.emission_interval, time::Duration::from_secs(5));
+    assert_eq!(raw_limit.burst_offset, time::Duration::from_secs(20));
+    let raw_limit = RawLimit::from(&Limit::new(1, 1, usize::MAX));
+    assert_eq!(raw_limit.emission_interval, time::Duration::MAX);
+    assert_eq!(raw_limit.burst_offset, time::Duration::MAX);
+
+    // A steady rate of one request per hour, with a one-time burst of 10.
+    let limiter = Limiter::in_memory();
+    let raw_limit = RawLimit {
+        emission_interval: time::Duration::from_secs(3600),
+        burst_offset: time::Duration::from_secs(10 * 3600),
+    };
+    let result = limiter
+        .allow_n_with_raw_limit("test_raw_limit", &raw_limit, 10)
+        .unwrap();
+    assert!(!result.limited);
+    assert_eq!(result.remaining, 0);
+    let result = limiter
+        .allow_n_with_raw_limit("test_raw_limit", &raw_limit, 1)
+        .unwrap();
+    assert!(result.limited);
+    assert!(result.retry_after.unwrap() > time::Duration::from_secs(3599));
+
+    let raw_limit = RawLimit {
+        emission_interval: time::Duration::ZERO,
+        ..raw_limit
+    };
+    assert!(matches!(
+        limiter.allow_n_with_raw_limit("test_raw_limit", &raw_limit, 1),
+        Err(RateLimitError::InvalidLimit(_))
+    ));
+}
+
+#[test]
+fn test_stats_snapshot() {
+    let limiter = Limiter::in_memory();
+    let limit = Limit::new(1, 2, 10);
+    for _ in 0..3 {
+        let _ = limiter.allow("test_stats_snapshot", &limit);
+    }
+    let _ = limiter.allow("", &limit);
+    let expected = LimiterStats {
+        allowed: 2,
+        denied: 1,
+        ..Default::default()
+    };
+    assert_eq!(limiter.clone().stats_snapshot(), expected);
+    assert_eq!(limiter.reset_stats(), expected);
+    assert_eq!(limiter.stats_snapshot(), LimiterStats::default());
+
+    let limiter = Limiter::in_memory().set_dry_run(true);
+    let _ = limiter.allow("test_stats_snapshot", &limit);
+    let _ = limiter.allow_n("test_stats_snapshot", &limit, 3);
+    assert_eq!(limiter.stats_snapshot(), LimiterStats::default());
+    assert_eq!(limiter.dry_run_denials(), 1);
+
+    let limiter = Limiter::new(redis::Client::open("redis://127.0.0.1:1/").unwrap());
+    let _ = limiter.allow("test_stats_snapshot", &limit);
+    assert_eq!(limiter.stats_snapshot().errors, 1);
+}
+
+#[test]
+fn test_retry_jitter() {
+    let limit = Limit::new(1, 1, 10);
+    let limiter = Limiter::in_memory()
+        .set_retry_jitter(time::Duration::from_secs(2))
+        .set_jitter_rng(|| 0.25);
+    assert_eq!(
+        limiter
+            .allow("test_retry_jitter", &limit)
+            .unwrap()
+            .retry_after,
+        None
+    );
+    let result = limiter.allow("test_retry_jitter", &limit).unwrap();
+    let retry_after = result.retry_after.unwrap();
+    assert!(retry_after > time::Duration::from_millis(10_490));
+    assert!(retry_after <= time::Duration::from_millis(10_500));
+    assert!(result.reset_after <= time::Duration::from_secs(10));
+}
+
+#[test]
+fn test_dry_run() {
+    let limiter = Limiter::in_memory().set_dry_run(true);
+    let limit = Limit::new(1, 2, 10);
+    for _ in 0..3 {
+        let result = limiter.allow_n("test_dry_run", &limit, 3).unwrap();
+        assert!(!result.limited);
+        assert!(result.retry_after.is_some());
+    }
+    assert_eq!(limiter.dry_run_denials(), 3);
+
+    let result = limiter.allow("test_dry_run", &limit).unwrap();
+    assert_eq!(result.remaining, 1);
+    assert_eq!(limiter.dry_run_denials(), 3);
+    assert_eq!(limiter.headroom("test_dry_run", &limit).unwrap(), 2);
+
+    // The other consuming checks peek as well.
+    let (granted, result) = limiter.allow_up_to("test_dry_run", &limit, 3).unwrap();
+    assert_eq!(granted, 3);
+    assert!(!result.limited);
+    let verbose = limiter.allow_n_verbose("test_dry_run", &limit, 2).unwrap();
+    assert_eq!(verbose.granted, 2);
+    let _ = limiter
+        .allow_n_with_burst_override("test_dry_run", &limit, 2, 5)
+        .unwrap();
+    let _ = limiter.try_allow_n("test_dry_run", &limit, 2).unwrap();
+    let _ = limiter
+        .allow_n_with_raw_limit("test_dry_run", &RawLimit::from(&limit), 3)
+        .unwrap();
+    let _ = limiter
+        .session(|session| session.allow_n("test_dry_run", &limit, 2))
+        .unwrap();
+    assert_eq!(limiter.dry_run_denials(), 4);
+    assert_eq!(limiter.headroom("test_dry_run", &limit).unwrap(), 2);
+
+    // A failing permit doesn't refund what was never consumed.
+    let strict = Limiter::in_memory();
+    let _ = strict.allow("test_dry_run", &limit).unwrap();
+    let limiter = Limiter::in_memory().set_dry_run(true);
+    let _ = limiter.allow("test_dry_run", &limit).unwrap();
+    let output = limiter.with_permit("test_dry_run", &limit, || Err::<(), _>("failed"));
+    assert!(matches!(output, Ok(Err("failed"))));
+    assert_eq!(limiter.headroom("test_dry_run", &limit).unwrap(), 2);
+}
+
+#[test]
+fn test_limit_as_ref() {
+    static LIMIT: std::sync::LazyLock<Limit> = std::sync::LazyLock::new(|| Limit::new(1, 5, 10));
+
+    let limiter = Limiter::in_memory();
+    let owned = limiter
+        .allow("test_limit_as_ref", Limit::new(1, 5, 10))
+        .unwrap();
+    let borrowed = limiter.allow("test_limit_as_ref", &*LIMIT).unwrap();
+    let lazy = limiter.allow("test_limit_as_ref", &LIMIT).unwrap();
+    assert_eq!(owned.remaining, 4);
+    assert_eq!(borrowed.remaining, 3);
+    assert_eq!(lazy.remaining, 2);
+    assert_eq!(
+        limiter.peek("test_limit_as_ref", &LIMIT).unwrap().remaining,
+        1
+    );
+}
+
+#[test]
+fn test_outcome() {
+    let mut result = LimitResult {
+        limited: false,
+        remaining: 3,
+        retry_after: None,
+        reset_after: time::Duration::from_secs(2),
+        limit: Limit::new(1, 5, 10),
+    };
+    assert_eq!(result.outcome(), Outcome::Allowed { remaining: 3 });
+
+    assert_eq!(result.retry_at(), None);
+    assert_eq!(result.to_http_parts().0, 200);
+    assert_eq!(result.to_http_parts().1.len(), 3);
+    assert!(result.reset_at() > time::SystemTime::now() + time::Duration::from_secs(1));
+
+    result.limited = true;
+    result.remaining = 0;
+    result.retry_after = Some(time::Duration::from_secs(1));
+    assert_eq!(
+        result.outcome(),
+        Outcome::Limited {
+            retry_after: time::Duration::from_secs(1),
+            remaining: 0,
+        }
+    );
+    let (status, headers) = result.to_http_parts();
+    assert_eq!(status, 429);
+    assert_eq!(
+        headers[0],
+        ("X-RateLimit-Limit".to_string(), "5".to_string())
+    );
+    assert_eq!(headers[3], ("Retry-After".to_string(), "1".to_string()));
+    let retry_at = result.retry_at().unwrap();
+    assert!(retry_at > time::SystemTime::now());
+    assert!(retry_at <= time::SystemTime::now() + time::Duration::from_secs(1));
+}
+
+#[test]
+fn test_clamp_future_tat() {
+    let limit = Limit::new(5, 5, 20);
+    let key = "test_clamp_future_tat";
+    let limiter = Limiter::new(redis::Client::open("redis://127.0.0.1/").unwrap());
+    limiter.reset(key).unwrap();
+
+    // Simulate a clock which moved 1000 seconds backward since the tat was stored,
+    // so the tat is far beyond a full burst in the future.
+    let mut con = limiter.client().unwrap().get_connection().unwrap();
+    let (now, _): (u64, u64) = redis::cmd("TIME").query(&mut con).unwrap();
+    let raw_key = format!("{}{}", DEFAULT_LIMITER_KEY_PREFIX, key);
+    redis::cmd("SET")
+        .arg(&raw_key)
+        .arg(now - JAN_1_2017 + 1000)
+        .arg("EX")
+        .arg(1020)
+        .query::<()>(&mut con)
+        .unwrap();
+
+    // The key is limited for at most a full burst, and the clamp is stored.
+    let result = limiter.allow(key, &limit).unwrap();
+    assert!(result.limited);
+    assert!(result.retry_after.unwrap() <= time::Duration::from_secs(4));
+    assert!(result.reset_after <= time::Duration::from_secs(20));
+    let ttl: i64 = redis::cmd("TTL").arg(&raw_key).query(&mut con).unwrap();
+    assert!(ttl <= 20);
+}
+
+#[test]
+fn test_clamp_future_tat_in_memory() {
+    let limit = Limit::new(5, 5, 20);
+    let key = "test_clamp_future_tat_in_memory";
+    let limiter = Limiter::in_memory();
+    let now = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs_f64();
+    limiter.import([(key.to_string(), now + 1000.0)]).unwrap();
+
+    let result = limiter.allow(key, &limit).unwrap();
+    assert!(result.limited);
+    assert!(result.retry_after.unwrap() <= time::Duration::from_secs(4));
+    let (_, tat) = limiter.export().unwrap().remove(0);
+    assert!(tat <= now + 21.0);
+}
+
+#[test]
+fn test_allow_default() {
+    let key = "test_allow_default";
+    let limiter = Limiter::in_memory();
+    assert!(matches!(
+        limiter.allow_default(key),
+        Err(RateLimitError::MissingDefaultLimit)
+    ));
+
+    let limiter = limiter.set_default_limit(Limit::new(2, 2, 20));
+    let result = limiter.allow_n_default(key, 2).unwrap();
+    assert!(!result.limited);
+    let result = limiter.allow_default(key).unwrap();
+    assert!(result.limited);
+}
+
+#[test]
+fn test_from_script_reply_saturates_remaining() {
+    let reply = redis::Value::Array(vec![
+        redis::Value::Nil,
+        redis::Value::BulkString(u64::MAX.to_string().into_bytes()),
+        redis::Value::Int(-1),
+        redis::Value::Int(10),
+    ]);
+    let result = RawLimitResult::from_script_reply(&reply).unwrap();
+    assert!(!result.limited);
+    assert_eq!(result.remaining, usize::MAX);
+    assert_eq!(result.retry_after, None);
+    assert_eq!(result.reset_after, time::Duration::from_micros(10));
+}
+
+#[test]
+fn test_implausible_result() {
+    let limiter = Limiter::in_memory();
+    let reply = redis::Value::Array(vec![
+        redis::Value::Nil,
+        redis::Value::Int(0),
+        redis::Value::Int(-1),
+        redis::Value::Int(i64::MAX),
+    ]);
+    let result = LimitResult::from_raw(
+        RawLimitResult::from_script_reply(&reply).unwrap(),
+        &Limit::new(1, 5, 10),
+    );
+    assert_eq!(
+        result.reset_after,
+        time::Duration::from_micros(i64::MAX as u64)
+    );
+    assert!(matches!(
+        limiter.check_plausible(result.clone(), 1),
+        Err(RateLimitError::ImplausibleResult(_))
+    ));
+
+    // A limit with a long burst can produce long durations.
+    let result = LimitResult {
+        reset_after: time::Duration::from_secs(30 * 24 * 60 * 60),
+        limit: Limit::new(1, 30, 24 * 60 * 60),
+        ..result
+    };
+    assert!(limiter.check_plausible(result.clone(), 1).is_ok());
+    let limiter = limiter.set_max_plausible_duration(time::Duration::from_secs(60));
+    assert!(limiter.check_plausible(result, 1).is_ok());
+}
+
+#[cfg(feature = "local_accelerate")]
+#[test]
+fn test_local_remaining_clamped() {
+    let limit = Limit::new(5, 5, 20);
+    let key = "test_local_remaining_clamped";
+    let limiter = Limiter::new(redis::Client::open("redis://127.0.0.1/").unwrap());
+
+    // A stale entry which resets far beyond the burst.
+    let now = time::Instant::now();
+    RESET_TIME_STORE.write().unwrap().insert(
+        format!("{}{}", DEFAULT_LIMITER_KEY_PREFIX, key),
+        LocalEntry {
+            reset_time: now + time::Duration::from_secs(100),
+            cached_at: now,
+        },
+    );
+
+    let result = limiter.allow(key, &limit).unwrap();
+    assert!(result.limited);
+    assert_eq!(result.remaining, 0);
+}
+
+#[cfg(feature = "local_accelerate")]
+#[test]
+fn test_local_cache_ttl() {
+    let limit = Limit::new(5, 5, 20);
+    let key = "test_local_cache_ttl";
+    let limiter = Limiter::new(redis::Client::open("redis://127.0.0.1/").unwrap())
+        .set_local_cache_ttl(time::Duration::from_secs(1));
+    limiter.reset(key).unwrap();
+
+    // An entry which would limit the request, but is cached too long ago to be trusted.
+    let now = time::Instant::now();
+    RESET_TIME_STORE.write().unwrap().insert(
+        format!("{}{}", DEFAULT_LIMITER_KEY_PREFIX, key),
+        LocalEntry {
+            reset_time: now + time::Duration::from_secs(100),
+            cached_at: now - time::Duration::from_secs(2),
+        },
+    );
+
+    let result = limiter.allow(key, &limit).unwrap();
+    assert!(!result.limited);
+    assert_eq!(result.remaining, 4);
+}
+
+#[cfg(feature = "local_accelerate")]
+#[test]
+fn test_cache_reset_time() {
+    let now = time::Instant::now();
+    for i in 0..100 {
+        cache_reset_time(
+            &format!("test_cache_reset_time:{i}"),
+            now,
+            time::Duration::from_millis(200),
+        );
+    }
+    let cached = |key: &str| RESET_TIME_STORE.read().unwrap().contains_key(key);
+    assert!(!(0..100).any(|i| cached(&format!("test_cache_reset_time:{i}"))));
+
+    let key = "test_cache_reset_time:long";
+    cache_reset_time(key, now, time::Duration::from_secs(10));
+    assert!(cached(key));
+    // A short reset drops the longer one cached before.
+    cache_reset_time(key, now, time::Duration::from_millis(200));
+    assert!(!cached(key));
+}
+
+#[test]
+fn test_failure_mode() {
+    let limit = Limit::new(1, 1, 10);
+    let key = "test_failure_mode";
+    // Nothing is listening on the port, so every call fails.
+    let limiter = Limiter::new(redis::Client::open("redis://127.0.0.1:1/").unwrap());
+    assert!(limiter.allow(key, &limit).is_err());
+
+    let result = limiter
+        .allow_n_with_policy(key, &limit, 1, FailureMode::Open)
+        .unwrap();
+    assert!(!result.limited);
+    assert!(matches!(
+        limiter.try_allow_n(key, &limit, 1),
+        Err(RateLimitError::WouldBlock(_))
+    ));
+
+    let limiter = limiter.set_failure_mode(FailureMode::Closed);
+    let result = limiter.allow(key, &limit).unwrap();
+    assert!(result.limited);
+    assert_eq!(result.retry_after, Some(time::Duration::from_secs(10)));
+    assert!(
+        limiter
+            .allow_n_with_policy(key, &limit, 1, FailureMode::Error)
+            .is_err()
+    );
+    let result = limiter.allow(key, Limit::new(1, 1, usize::MAX)).unwrap();
+    assert_eq!(result.retry_after, Some(time::Duration::MAX));
+}
+
+#[test]
+fn test_try_allow_n_error() {
+    // A server which rejects the password, so connecting fails without a timeout or refusal.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+        use std::io::{Read, Write};
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0; 1024];
+        // Reply an error to every command, the client may pipeline several on connect.
+        while let Ok(len @ 1..) = stream.read(&mut buf) {
+            for _ in buf[..len].iter().filter(|&&b| b == b'*') {
+                let _ = stream.write_all(b"-ERR invalid password\r\n");
+            }
+        }
+    });
+    let client = redis::Client::open(format!("redis://:wrong@127.0.0.1:{port}/")).unwrap();
+    let limiter = Limiter::new(client).set_try_timeout(time::Duration::from_secs(5));
+    let result = limiter.try_allow_n("test_try_allow_n_error", &Limit::new(1, 1, 10), 1);
+    assert!(matches!(result, Err(RateLimitError::Redis(_))));
+}
+
+#[test]
+fn test_headroom() {
+    let limit = Limit::new(5, 5, 20);
+    let key = "test_headroom";
+    let limiter = Limiter::new(redis::Client::open("redis://127.0.0.1/").unwrap());
+    limiter.reset(key).unwrap();
+
+    assert_eq!(limiter.headroom(key, &limit).unwrap(), 5);
+    let _ = limiter.allow_n(key, &limit, 3).unwrap();
+    assert_eq!(limiter.headroom(key, &limit).unwrap(), 2);
+    // Peeking doesn't consume.
+    assert!(!limiter.peek(key, &limit).unwrap().limited);
+    assert_eq!(limiter.headroom(key, &limit).unwrap(), 2);
+}
+
+#[test]
+fn test_noscript_recovery() {
+    let limit = Limit::new(5, 5, 20);
+    let key = "test_noscript_recovery";
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let limiter = Limiter::new(client.clone());
+    limiter.reset(key).unwrap();
+    let mut con = client.get_connection().unwrap();
+    // Drop the cached scripts like a Redis restart does,
+    // so the next calls get NOSCRIPT and have to load the scripts again.
+    let mut flush = || {
+        redis::cmd("SCRIPT")
+            .arg("FLUSH")
+            .query::<()>(&mut con)
+            .unwrap()
+    };
+
+    flush();
+    assert_eq!(limiter.allow_n(key, &limit, 2).unwrap().remaining, 3);
+    flush();
+    limiter.refund(key, &limit).unwrap();
+    flush();
+    assert_eq!(limiter.headroom(key, &limit).unwrap(), 4);
+}
+
+#[test]
+fn test_ttl_rounding() {
+    let limit = Limit::new(2, 2, 5);
+    let key = "test_ttl_rounding";
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    for (ttl_rounding, ttl) in [(TtlRounding::Ceil, 3), (TtlRounding::Floor, 2)] {
+        let limiter = Limiter::new(client.clone()).set_ttl_rounding(ttl_rounding);
+        limiter.reset(key).unwrap();
+        // The limit is totally reset 2.5 seconds after one request.
+        let _ = limiter.allow(key, &limit).unwrap();
+        let actual: i64 = redis::cmd("TTL")
+            .arg(format!("{DEFAULT_LIMITER_KEY_PREFIX}{key}"))
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(actual, ttl);
+    }
+}
+
+#[test]
+fn test_reset_after_precision() {
+    let key = "test_reset_after_precision";
+    let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let mut con = client.get_connection().unwrap();
+    let limiter = Limiter::new(client);
+
+    for (limit, reset_after) in [
+        (Limit::new(4, 4, 1), time::Duration::from_millis(250)),
+        (
+            Limit::new(1, 1, 100_000),
+            time::Duration::from_secs(100_000),
+        ),
+    ] {
+        limiter.reset(key).unwrap();
+        let result = limiter.allow(key, &limit).unwrap();
+        // Only the time passed in the script is lost.
+        assert!(result.reset_after <= reset_after);
+        assert!(result.reset_after > reset_after - time::Duration::from_millis(10));
+        let ttl: u64 = redis::cmd("TTL")
+            .arg(format!("{DEFAULT_LIMITER_KEY_PREFIX}{key}"))
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(ttl, result.reset_after.as_secs_f64().ceil() as u64);
+    }
+}
+
+#[test]
+fn test_preload_script() {
+    let limiter = Limiter::new(redis::Client::open("redis://127.0.0.1/").unwrap());
+    assert_eq!(limiter.preload_script().unwrap(), ALLOW_N_SCRIPT.get_hash());
+}
+
+#[test]
+fn test_limit_scoped_keys() {
+    let limit_a = Limit::new(1, 1, 10);
+    let limit_b = Limit::new(1, 1, 20);
+    let key = "test_limit_scoped_keys";
+    let limiter = Limiter::new(redis::Client::open("redis://127.0.0.1/").unwrap())
+        .set_limit_scoped_keys(true);
+    limiter.reset(key).unwrap();
+
+    assert!(!limiter.allow(key, &limit_a).unwrap().limited);
+    // The other limit doesn't share the state.
+    assert!(!limiter.allow(key, &limit_b).unwrap().limited);
+    assert!(limiter.allow(key, &limit_a).unwrap().limited);
+
+    limiter.reset(key).unwrap();
+    assert!(!limiter.allow(key, &limit_a).unwrap().limited);
+    assert!(!limiter.allow(key, &limit_b).unwrap().limited);
+}
+
+#[test]
+fn test_in_memory_limiter() {
+    let limit = Limit::new(5, 5, 20);
+    let key = "test";
+    let limiter = Limiter::in_memory();
+
+    let result = limiter.allow_n(key, &limit, 4).unwrap();
+    assert!(!result.limited);
+    assert_eq!(result.remaining, 1);
+    let result = limiter.allow_n(key, &limit, 3).unwrap();
+    assert!(result.limited);
+    assert_eq!(result.remaining, 1);
+    assert_eq!(limiter.headroom(key, &limit).unwrap(), 1);
+    assert!(!limiter.peek(key, &limit).unwrap().limited);
+    let result = limiter.allow(key, &limit).unwrap();
+    assert!(!result.limited);
+    assert_eq!(result.remaining, 0);
+    assert_eq!(limiter.keys().unwrap(), vec![key]);
+
+    let result = limiter.allow_n(key, &limit, 5).unwrap();
+    assert!(result.limited);
+    limiter.reset(key).unwrap();
 
     let result = limiter.allow_n(key, &limit, 5).unwrap();
-    assert_eq!(result.limited, false);
+    assert!(!result.limited);
+}
+
+#[test]
+fn test_allow_n_verbose() {
+    let limit = Limit::new(5, 5, 20);
+    let key = "test_allow_n_verbose";
+    let limiter = Limiter::in_memory();
+
+    let verbose = limiter.allow_n_verbose(key, &limit, 1).unwrap();
+    assert!(verbose.first_seen);
+    assert!(!verbose.result.limited);
+    let verbose = limiter.allow_n_verbose(key, &limit, 1).unwrap();
+    assert!(!verbose.first_seen);
+    assert_eq!(verbose.result.remaining, 3);
+    assert_eq!(verbose.result.limit, limit);
+
+    limiter.reset(key).unwrap();
+    assert!(limiter.allow_n_verbose(key, &limit, 1).unwrap().first_seen);
+}
+
+#[test]
+fn test_session() {
+    let limit = Limit::new(5, 5, 20);
+    let key = "test_session";
+    let limiter = Limiter::in_memory();
+
+    let remaining = limiter
+        .session(|session| {
+            let _ = session.allow_n(key, &limit, 2)?;
+            let _ = session.allow_n(key, &limit, 2)?;
+            session.allow(key, &limit).map(|result| result.remaining)
+        })
+        .unwrap();
+    assert_eq!(remaining, 0);
+    assert!(limiter.allow(key, &limit).unwrap().limited);
+}
+
+#[test]
+fn test_glob_match() {
+    assert!(glob_match("user:123:*", "user:123:login"));
+    assert!(!glob_match("user:123:*", "user:1234"));
+    assert!(glob_match("h?llo", "hello"));
+    assert!(glob_match("h[a-e]llo", "hello"));
+    assert!(!glob_match("h[^e]llo", "hello"));
+    assert!(glob_match("a\\*", "a*"));
+    assert!(!glob_match("a\\*", "ab"));
+    assert!(glob_match(&escape_glob_pattern("[x]*"), "[x]*"));
+}
+
+#[test]
+fn test_reset_pattern() {
+    let limit = Limit::new(5, 5, 20);
+    let limiter = Limiter::in_memory();
+    let _ = limiter.allow("user:123:login", &limit).unwrap();
+    let _ = limiter.allow("user:123:upload", &limit).unwrap();
+    let _ = limiter.allow("user:1234:login", &limit).unwrap();
+
+    assert_eq!(limiter.reset_pattern("user:123:*").unwrap(), 2);
+    assert_eq!(limiter.keys().unwrap(), vec!["user:1234:login"]);
+    assert!(
+        Limiter::in_memory()
+            .set_key_prefix("")
+            .reset_pattern("*")
+            .is_err()
+    );
+}
+
+#[test]
+fn test_namespace() {
+    let limit = Limit::new(1, 1, 20);
+    let limiter = Limiter::in_memory();
+    let staging = limiter.clone().set_namespace("staging");
+    let prod = limiter.set_namespace("prod");
+
+    assert!(!staging.allow("user", &limit).unwrap().limited);
+    assert!(!prod.allow("user", &limit).unwrap().limited);
+    assert!(staging.allow("user", &limit).unwrap().limited);
+    assert_eq!(staging.keys().unwrap(), vec!["user"]);
+
+    assert_eq!(staging.reset_pattern("*").unwrap(), 1);
+    assert!(!staging.allow("user", &limit).unwrap().limited);
+    assert!(prod.allow("user", &limit).unwrap().limited);
+}
+
+#[test]
+fn test_grant() {
+    let limit = Limit::new(5, 5, 20);
+    let key = "test_grant";
+    let limiter = Limiter::in_memory();
+
+    let _ = limiter.allow_n(key, &limit, 5).unwrap();
+    assert_eq!(limiter.grant(key, &limit, 2).unwrap(), 2);
+    assert_eq!(limiter.grant(key, &limit, 10).unwrap(), 5);
+}
+
+#[test]
+fn test_export_import() {
+    let limit = Limit::new(5, 5, 20);
+    let source = Limiter::in_memory();
+    let _ = source.allow_n("a", &limit, 2).unwrap();
+    let _ = source.allow_n("b", &limit, 5).unwrap();
+
+    assert_eq!(
+        source.export_iter().unwrap().count(),
+        source.export().unwrap().len()
+    );
+    let mut entries = source.export().unwrap();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].0, "a");
+
+    let target = Limiter::in_memory();
+    let expired = ("c".to_string(), 0.0);
+    assert_eq!(
+        target.import(entries.into_iter().chain([expired])).unwrap(),
+        2
+    );
+    assert_eq!(target.headroom("a", &limit).unwrap(), 3);
+    assert!(target.allow("b", &limit).unwrap().limited);
+}
+
+#[test]
+fn test_allow_up_to() {
+    let limit = Limit::new(5, 5, 20);
+    let key = "test_allow_up_to";
+    let limiter = Limiter::in_memory();
+
+    // Checking 0 requests consumes nothing.
+    let (granted, result) = limiter.allow_up_to(key, &limit, 0).unwrap();
+    assert_eq!((granted, result.limited, result.remaining), (0, false, 5));
+    let _ = limiter.allow_n(key, &limit, 0).unwrap();
+    assert_eq!(limiter.headroom(key, &limit).unwrap(), 5);
+
+    let (granted, result) = limiter.allow_up_to(key, &limit, 3).unwrap();
+    assert_eq!((granted, result.remaining), (3, 2));
+    let (granted, result) = limiter.allow_up_to(key, &limit, 3).unwrap();
+    assert_eq!((granted, result.remaining), (2, 0));
+    assert!(!result.limited);
+    let (granted, result) = limiter.allow_up_to(key, &limit, 3).unwrap();
+    assert_eq!(granted, 0);
+    assert!(result.limited);
+    assert!(result.retry_after.unwrap() <= time::Duration::from_secs(4));
+}
+
+#[test]
+fn test_fixed_window() {
+    let limit = Limit::new(2, 2, 3600);
+    let key = "test_fixed_window";
+    let limiter = Limiter::new(redis::Client::open("redis://127.0.0.1/").unwrap()).set_algorithm(
+        Algorithm::FixedWindow {
+            retention: time::Duration::from_secs(60),
+        },
+    );
+    let now = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let window_start = time::UNIX_EPOCH + time::Duration::from_secs(now - now % 3600);
+    let count = limiter.window_count(key, &limit, window_start).unwrap();
+
+    let result = limiter.allow(key, &limit).unwrap();
+    assert_eq!(result.limited, count >= 2);
+    assert_eq!(
+        limiter.window_count(key, &limit, window_start).unwrap(),
+        (count + 1).min(2)
+    );
+
+    // Peeks and dry runs read the window counter without incrementing it.
+    let count = limiter.window_count(key, &limit, window_start).unwrap();
+    assert_eq!(limiter.headroom(key, &limit).unwrap(), 2 - count);
+    let dry_run = limiter.clone().set_dry_run(true);
+    let _ = dry_run.allow_n(key, &limit, 3).unwrap();
+    assert_eq!(dry_run.dry_run_denials(), 1);
+    assert_eq!(
+        limiter.window_count(key, &limit, window_start).unwrap(),
+        count
+    );
+}
+
+#[test]
+fn test_fixed_window_reset_refund() {
+    let limit = Limit::new(2, 2, 3600);
+    let key = "test_fixed_window_reset_refund";
+    let limiter = Limiter::new(redis::Client::open("redis://127.0.0.1/").unwrap())
+        .set_algorithm(Algorithm::FixedWindow {
+            retention: time::Duration::from_secs(60),
+        })
+        .set_limit_scoped_keys(true);
+    let now = time::SystemTime::now();
+    limiter.reset(key).unwrap();
+
+    let _ = limiter.allow_n(key, &limit, 2).unwrap();
+    assert_eq!(limiter.window_count(key, &limit, now).unwrap(), 2);
+    assert_eq!(limiter.grant(key, &limit, 1).unwrap(), 1);
+    assert_eq!(limiter.window_count(key, &limit, now).unwrap(), 1);
+
+    // A failing permit gives its request back to the window.
+    let output = limiter.with_permit(key, &limit, || Err::<(), _>("failed"));
+    assert!(matches!(output, Ok(Err("failed"))));
+    assert_eq!(limiter.window_count(key, &limit, now).unwrap(), 1);
+
+    limiter.reset(key).unwrap();
+    assert_eq!(limiter.window_count(key, &limit, now).unwrap(), 0);
+    assert_eq!(limiter.headroom(key, &limit).unwrap(), 2);
+}
+
+#[test]
+fn test_keys() {
+    let limit = Limit::new(1, 1, 20);
+    let limiter = Limiter::new(redis::Client::open("redis://127.0.0.1/").unwrap())
+        .set_key_prefix("redis_rate_test_keys:");
+    for key in limiter.keys().unwrap() {
+        limiter.reset(&key).unwrap();
+    }
+
+    let _ = limiter.allow("b", &limit).unwrap();
+    let _ = limiter.allow("a", &limit).unwrap();
+    assert_eq!(limiter.keys().unwrap(), vec!["a", "b"]);
+}
+
+#[test]
+fn test_keys_sorted() {
+    let limit = Limit::new(1, 1, 20);
+    let limiter = Limiter::in_memory();
+    for key in ["c", "a", "b"] {
+        let _ = limiter.allow(key, &limit).unwrap();
+    }
+    assert_eq!(limiter.keys().unwrap(), vec!["a", "b", "c"]);
+}
+
+#[test]
+fn test_with_permit() {
+    let limit = Limit::new(1, 1, 20);
+    let key = "test_with_permit";
+    let limiter = Limiter::in_memory();
+
+    let output = limiter.with_permit(key, &limit, || Err::<(), _>("failed"));
+    assert!(matches!(output, Ok(Err("failed"))));
+    // The failed operation is refunded, so the permit is available again.
+    let output = limiter.with_permit(key, &limit, || Ok::<_, ()>(1));
+    assert!(matches!(output, Ok(Ok(1))));
+    let output = limiter.with_permit(key, &limit, || Ok::<_, ()>(2));
+    assert!(matches!(output, Err(RateLimitError::Limited(_))));
 }
@@ -0,0 +1,159 @@
+use std::{collections::HashMap, sync::Mutex, time};
+
+use crate::gcra_decide;
+
+/// Number of keys from which the expired tats are swept.
+const MIN_SWEEP_LEN: usize = 1024;
+
+/// In-process store of the tats, used by `Limiter::in_memory`.
+/// The tats are seconds since the Unix epoch, with the same GCRA math as the Redis scripts.
+///
+/// Expired tats are ignored when looked up, and dropped like keys expiring in Redis
+/// by a sweep whenever the number of keys doubles since the last one,
+/// so a check only costs a sweep of all the keys once in a while.
+#[derive(Debug, Default)]
+pub(crate) struct MemoryStore {
+    tats: Mutex<Tats>,
+}
+
+#[derive(Debug, Default)]
+struct Tats {
+    tats: HashMap<String, f64>,
+    /// Number of keys from which the next sweep is done.
+    sweep_len: usize,
+}
+
+impl Tats {
+    /// The tat of the key, unless it is expired.
+    fn get(&self, key: &str, now: f64) -> Option<f64> {
+        self.tats.get(key).copied().filter(|tat| *tat > now)
+    }
+
+    fn insert(&mut self, key: String, tat: f64, now: f64) {
+        self.tats.insert(key, tat);
+        if self.tats.len() >= self.sweep_len.max(MIN_SWEEP_LEN) {
+            self.tats.retain(|_, tat| *tat > now);
+            self.sweep_len = self.tats.len() * 2;
+        }
+    }
+}
+
+impl MemoryStore {
+    /// Decide on n requests, and store the new tat if `commit` and the requests are allowed.
+    /// If `partial`, decide on as many of the requests as available, like the allow script.
+    /// Also returns whether the key had no tat before, and the number of granted requests.
+    pub(crate) fn decide(
+        &self,
+        key: &str,
+        emission_interval: f64,
+        burst_offset: f64,
+        n: usize,
+        partial: bool,
+        commit: bool,
+    ) -> ((bool, usize, f64, f64), bool, usize) {
+        let now = now_secs();
+        let mut tats = self.tats.lock().unwrap_or_else(|e| e.into_inner());
+        let tat = tats.get(key, now);
+        let first_seen = tat.is_none();
+        let tat = tat.unwrap_or(now);
+        // Store the clamp of a tat left in the future by a backward clock,
+        // like the allow script, so the key is limited for at most a full burst.
+        if commit && tat > now + burst_offset {
+            tats.insert(key.to_string(), now + burst_offset, now);
+        }
+        let mut granted = n;
+        if partial {
+            let (_, available, _, _) = gcra_decide(now, tat, emission_interval, burst_offset, 0.0);
+            granted = n.min(available);
+        }
+        // With none available, a single request only computes `retry_after` of the limited check.
+        let tat_increment = if granted == 0 && n > 0 {
+            emission_interval
+        } else {
+            granted as f64 * emission_interval
+        };
+        let decision = gcra_decide(now, tat, emission_interval, burst_offset, tat_increment);
+        let (limited, _, _, reset_after) = decision;
+        if limited {
+            granted = 0;
+        } else if commit && granted > 0 {
+            // Checking 0 requests is a peek, which writes nothing.
+            tats.insert(key.to_string(), now + reset_after, now);
+        }
+        (decision, first_seen, granted)
+    }
+
+    /// Move the tat of the key backward, never before now.
+    pub(crate) fn refund(&self, key: &str, tat_decrement: f64) {
+        let now = now_secs();
+        let mut tats = self.tats.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(tat) = tats.tats.get_mut(key) {
+            *tat = (*tat - tat_decrement).max(now);
+        }
+    }
+
+    /// Remove the keys matching the predicate, and return the number of removed keys.
+    pub(crate) fn remove(&self, mut predicate: impl FnMut(&str) -> bool) -> usize {
+        let mut tats = self.tats.lock().unwrap_or_else(|e| e.into_inner());
+        let len = tats.tats.len();
+        tats.tats.retain(|key, _| !predicate(key));
+        len - tats.tats.len()
+    }
+
+    /// List the keys which are not expired, with their tats.
+    pub(crate) fn entries(&self) -> Vec<(String, f64)> {
+        let now = now_secs();
+        let tats = self.tats.lock().unwrap_or_else(|e| e.into_inner());
+        tats.tats
+            .iter()
+            .filter(|(_, tat)| **tat > now)
+            .map(|(key, tat)| (key.clone(), *tat))
+            .collect()
+    }
+
+    /// Store the tat of a key, unless it is already expired.
+    /// Returns whether the tat is stored.
+    pub(crate) fn insert(&self, key: String, tat: f64) -> bool {
+        let now = now_secs();
+        if tat <= now {
+            return false;
+        }
+        let mut tats = self.tats.lock().unwrap_or_else(|e| e.into_inner());
+        tats.insert(key, tat, now);
+        true
+    }
+
+    /// List the keys which are not expired.
+    pub(crate) fn keys(&self) -> Vec<String> {
+        let now = now_secs();
+        let tats = self.tats.lock().unwrap_or_else(|e| e.into_inner());
+        tats.tats
+            .iter()
+            .filter(|(_, tat)| **tat > now)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+}
+
+fn now_secs() -> f64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+#[test]
+fn test_sweep_expired_tats() {
+    let mut tats = Tats::default();
+    for i in 0..MIN_SWEEP_LEN - 1 {
+        tats.insert(format!("expired:{i}"), 1.0, 2.0);
+    }
+    assert_eq!(tats.get("expired:0", 2.0), None);
+    assert_eq!(tats.tats.len(), MIN_SWEEP_LEN - 1);
+
+    // Reaching the threshold sweeps the expired tats, and doubles it from the kept ones.
+    tats.insert("live".to_string(), 3.0, 2.0);
+    assert_eq!(tats.tats.len(), 1);
+    assert_eq!(tats.sweep_len, 2);
+    assert_eq!(tats.get("live", 2.0), Some(3.0));
+}
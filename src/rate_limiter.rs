@@ -0,0 +1,182 @@
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::time;
+
+use crate::{Limit, LimitResult, Limiter, RateLimitError};
+
+/// Common interface of rate limiters,
+/// so code can be generic over `impl RateLimiter` and use `NoopRateLimiter` in tests.
+///
+/// The methods are blocking like the ones of `Limiter`,
+/// see `AsyncRateLimiter` with the `async` feature for async code.
+pub trait RateLimiter {
+    /// Allow a request to be made within the limit.
+    fn allow(&self, key: &str, limit: &Limit) -> Result<LimitResult, RateLimitError> {
+        self.allow_n(key, limit, 1)
+    }
+
+    /// Allow n requests to be made within the limit.
+    fn allow_n(&self, key: &str, limit: &Limit, n: usize) -> Result<LimitResult, RateLimitError>;
+
+    /// Reset the limit for a key.
+    fn reset(&self, key: &str) -> Result<(), RateLimitError>;
+}
+
+impl RateLimiter for Limiter {
+    fn allow_n(&self, key: &str, limit: &Limit, n: usize) -> Result<LimitResult, RateLimitError> {
+        Limiter::allow_n(self, key, limit, n)
+    }
+
+    fn reset(&self, key: &str) -> Result<(), RateLimitError> {
+        Limiter::reset(self, key)
+    }
+}
+
+/// Rate limiter which allows every request, with the full burst remaining.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopRateLimiter;
+
+impl RateLimiter for NoopRateLimiter {
+    fn allow_n(&self, _key: &str, limit: &Limit, _n: usize) -> Result<LimitResult, RateLimitError> {
+        Ok(LimitResult {
+            limited: false,
+            remaining: limit.burst,
+            retry_after: None,
+            reset_after: time::Duration::ZERO,
+            limit: limit.clone(),
+        })
+    }
+
+    fn reset(&self, _key: &str) -> Result<(), RateLimitError> {
+        Ok(())
+    }
+}
+
+/// Async variant of `RateLimiter`, enabled by the `async` feature.
+///
+/// `Limiter` implements it by running the blocking calls on the blocking thread pool of Tokio,
+/// so the methods must be awaited within a Tokio runtime.
+/// The futures are `Send`, so they can be awaited in spawned tasks and axum handlers.
+#[cfg(feature = "async")]
+pub trait AsyncRateLimiter {
+    /// Allow a request to be made within the limit.
+    fn allow(
+        &self,
+        key: &str,
+        limit: &Limit,
+    ) -> impl Future<Output = Result<LimitResult, RateLimitError>> + Send {
+        self.allow_n(key, limit, 1)
+    }
+
+    /// Allow n requests to be made within the limit.
+    fn allow_n(
+        &self,
+        key: &str,
+        limit: &Limit,
+        n: usize,
+    ) -> impl Future<Output = Result<LimitResult, RateLimitError>> + Send;
+
+    /// Reset the limit for a key.
+    fn reset(&self, key: &str) -> impl Future<Output = Result<(), RateLimitError>> + Send;
+}
+
+#[cfg(feature = "async")]
+impl AsyncRateLimiter for Limiter {
+    fn allow_n(
+        &self,
+        key: &str,
+        limit: &Limit,
+        n: usize,
+    ) -> impl Future<Output = Result<LimitResult, RateLimitError>> + Send {
+        let (limiter, key, limit) = (self.clone(), key.to_string(), limit.clone());
+        spawn_blocking(move || limiter.allow_n(&key, &limit, n))
+    }
+
+    fn reset(&self, key: &str) -> impl Future<Output = Result<(), RateLimitError>> + Send {
+        let (limiter, key) = (self.clone(), key.to_string());
+        spawn_blocking(move || limiter.reset(&key))
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncRateLimiter for NoopRateLimiter {
+    fn allow_n(
+        &self,
+        key: &str,
+        limit: &Limit,
+        n: usize,
+    ) -> impl Future<Output = Result<LimitResult, RateLimitError>> + Send {
+        std::future::ready(RateLimiter::allow_n(self, key, limit, n))
+    }
+
+    fn reset(&self, key: &str) -> impl Future<Output = Result<(), RateLimitError>> + Send {
+        std::future::ready(RateLimiter::reset(self, key))
+    }
+}
+
+/// Run a blocking call on the blocking thread pool of Tokio.
+/// A panic of the call is resumed in the caller.
+#[cfg(feature = "async")]
+async fn spawn_blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    match tokio::task::spawn_blocking(f).await {
+        Ok(output) => output,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+#[test]
+fn test_rate_limiter() {
+    fn check(limiter: &impl RateLimiter, limit: &Limit) -> bool {
+        limiter
+            .allow_n("test_rate_limiter", limit, 2)
+            .unwrap()
+            .limited
+    }
+
+    let limit = Limit::new(1, 1, 10);
+    assert!(!check(&NoopRateLimiter, &limit));
+    assert!(!check(&NoopRateLimiter, &limit));
+
+    let limiter = Limiter::in_memory();
+    assert!(limiter.allow("test_rate_limiter", &limit).is_ok());
+    assert!(check(&limiter, &limit));
+    RateLimiter::reset(&limiter, "test_rate_limiter").unwrap();
+    assert!(
+        !RateLimiter::allow(&limiter, "test_rate_limiter", &limit)
+            .unwrap()
+            .limited
+    );
+}
+
+#[cfg(all(test, feature = "async"))]
+#[tokio::test]
+async fn test_async_rate_limiter() {
+    async fn check(limiter: &impl AsyncRateLimiter, limit: &Limit) -> bool {
+        limiter
+            .allow_n("test_async_rate_limiter", limit, 2)
+            .await
+            .unwrap()
+            .limited
+    }
+
+    let limit = Limit::new(1, 1, 10);
+    assert!(!check(&NoopRateLimiter, &limit).await);
+
+    let limiter = Limiter::in_memory();
+    assert!(
+        !AsyncRateLimiter::allow(&limiter, "test_async_rate_limiter", &limit)
+            .await
+            .unwrap()
+            .limited
+    );
+    assert!(check(&limiter, &limit).await);
+    AsyncRateLimiter::reset(&limiter, "test_async_rate_limiter")
+        .await
+        .unwrap();
+    assert!(
+        !AsyncRateLimiter::allow(&limiter, "test_async_rate_limiter", &limit)
+            .await
+            .unwrap()
+            .limited
+    );
+}
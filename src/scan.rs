@@ -0,0 +1,225 @@
+use std::vec;
+
+use crate::{EXPORT_BATCH_SIZE, LIMIT_FINGERPRINT_SEPARATOR, Limit, strip_window_start};
+
+/// Pages of keys matching a pattern, fetched with `SCAN` one page at a time.
+struct ScanPages {
+    con: redis::Connection,
+    pattern: String,
+    /// Cursor of the next page, `None` when the scan is done.
+    cursor: Option<u64>,
+}
+
+impl ScanPages {
+    fn next_page(&mut self) -> Option<Result<Vec<String>, redis::RedisError>> {
+        let cursor = self.cursor?;
+        let page = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(&self.pattern)
+            .arg("COUNT")
+            .arg(EXPORT_BATCH_SIZE)
+            .query::<(u64, Vec<String>)>(&mut self.con);
+        match page {
+            Ok((next, keys)) => {
+                self.cursor = (next != 0).then_some(next);
+                Some(Ok(keys))
+            }
+            Err(e) => {
+                self.cursor = None;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Iterator over the keys with an active limit, see `Limiter::keys_iter`.
+pub struct KeysIter {
+    pages: Option<ScanPages>,
+    page: vec::IntoIter<String>,
+    prefix: String,
+    limit_scoped_keys: bool,
+    /// Whether the raw keys are fixed window counters, whose window start is stripped.
+    fixed_window: bool,
+}
+
+impl KeysIter {
+    pub(crate) fn new(
+        con: redis::Connection,
+        pattern: String,
+        prefix: String,
+        limit_scoped_keys: bool,
+        fixed_window: bool,
+    ) -> Self {
+        KeysIter {
+            pages: Some(ScanPages {
+                con,
+                pattern,
+                cursor: Some(0),
+            }),
+            page: Vec::new().into_iter(),
+            prefix,
+            limit_scoped_keys,
+            fixed_window,
+        }
+    }
+
+    /// Iterate over raw keys which are already known, e.g. by the in-memory limiter.
+    pub(crate) fn from_raw_keys(
+        raw_keys: Vec<String>,
+        prefix: String,
+        limit_scoped_keys: bool,
+    ) -> Self {
+        KeysIter {
+            pages: None,
+            page: raw_keys.into_iter(),
+            prefix,
+            limit_scoped_keys,
+            fixed_window: false,
+        }
+    }
+
+    /// Strip the key prefix, the window start of fixed window counters,
+    /// and the limit fingerprint with limit scoped keys.
+    fn strip(&self, raw_key: &str) -> Option<String> {
+        let key = raw_key.strip_prefix(self.prefix.as_str())?;
+        let key = if self.fixed_window {
+            strip_window_start(key)?
+        } else {
+            key
+        };
+        let key = match key.rsplit_once(LIMIT_FINGERPRINT_SEPARATOR) {
+            Some((key, fingerprint))
+                if self.limit_scoped_keys && Limit::is_fingerprint(fingerprint) =>
+            {
+                key
+            }
+            _ => key,
+        };
+        Some(key.to_string())
+    }
+}
+
+impl Iterator for KeysIter {
+    type Item = Result<String, redis::RedisError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(raw_key) = self.page.next() {
+                match self.strip(&raw_key) {
+                    Some(key) => return Some(Ok(key)),
+                    None => continue,
+                }
+            }
+            match self.pages.as_mut()?.next_page()? {
+                Ok(raw_keys) => self.page = raw_keys.into_iter(),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Iterator over the exported state of the keys, see `Limiter::export_iter`.
+pub struct ExportIter {
+    pages: Option<ScanPages>,
+    page: vec::IntoIter<(String, f64)>,
+    prefix: String,
+    time_epoch: u64,
+    time_scale: f64,
+}
+
+impl ExportIter {
+    pub(crate) fn new(
+        con: redis::Connection,
+        pattern: String,
+        prefix: String,
+        (time_epoch, time_scale): (u64, f64),
+    ) -> Self {
+        ExportIter {
+            pages: Some(ScanPages {
+                con,
+                pattern,
+                cursor: Some(0),
+            }),
+            page: Vec::new().into_iter(),
+            prefix,
+            time_epoch,
+            time_scale,
+        }
+    }
+
+    /// Iterate over entries which are already exported, e.g. by the in-memory limiter.
+    pub(crate) fn from_entries(entries: Vec<(String, f64)>) -> Self {
+        ExportIter {
+            pages: None,
+            page: entries.into_iter(),
+            prefix: String::new(),
+            time_epoch: 0,
+            time_scale: 1.0,
+        }
+    }
+
+    /// Read the tats of a page of raw keys with `MGET`.
+    fn read_page(
+        &mut self,
+        raw_keys: Vec<String>,
+    ) -> Result<Vec<(String, f64)>, redis::RedisError> {
+        let Some(pages) = self.pages.as_mut() else {
+            return Ok(Vec::new());
+        };
+        if raw_keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let tats: Vec<Option<String>> = redis::cmd("MGET").arg(&raw_keys).query(&mut pages.con)?;
+        Ok(raw_keys
+            .into_iter()
+            .zip(tats)
+            .filter_map(|(raw_key, tat)| {
+                // Keys may expire between the scan and the read.
+                let tat = tat?.parse::<f64>().ok()?;
+                let key = raw_key.strip_prefix(self.prefix.as_str())?.to_string();
+                Some((key, tat / self.time_scale + self.time_epoch as f64))
+            })
+            .collect())
+    }
+}
+
+impl Iterator for ExportIter {
+    type Item = Result<(String, f64), redis::RedisError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.page.next() {
+                return Some(Ok(entry));
+            }
+            let raw_keys = match self.pages.as_mut()?.next_page()? {
+                Ok(raw_keys) => raw_keys,
+                Err(e) => return Some(Err(e)),
+            };
+            match self.read_page(raw_keys) {
+                Ok(entries) => self.page = entries.into_iter(),
+                Err(e) => {
+                    self.pages = None;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_keys_iter_fixed_window() {
+    let mut keys = KeysIter::from_raw_keys(
+        vec![
+            "p:user:1:1700000000".to_string(),
+            "p:user:1:1700003600".to_string(),
+            "p:user:2#5/5/10:1700000000".to_string(),
+            "p:stray".to_string(),
+        ],
+        "p:".to_string(),
+        true,
+    );
+    keys.fixed_window = true;
+    let keys: Vec<String> = keys.map(Result::unwrap).collect();
+    assert_eq!(keys, vec!["user:1", "user:1", "user:2"]);
+}
@@ -1,11 +1,12 @@
+#[cfg(not(target_arch = "wasm32"))]
 use std::sync::LazyLock;
 
-pub(crate) static ALLOW_N_SCRIPT: LazyLock<redis::Script> = LazyLock::new(|| {
-    // This is an edited version of the script from the redis-gcra project:
-    // Copyright (c) 2017 Pavel Pravosud
-    // https://github.com/rwz/redis-gcra/blob/master/vendor/perform_gcra_ratelimit.lua
-    redis::Script::new(
-        r#"-- this script has side-effects, so it requires replicate commands mode
+// This is an edited version of the script from the redis-gcra project:
+// Copyright (c) 2017 Pavel Pravosud
+// https://github.com/rwz/redis-gcra/blob/master/vendor/perform_gcra_ratelimit.lua
+// Keep the math in sync with `gcra::gcra_decide`.
+/// Lua source of the GCRA script run by `Limiter::allow_n`.
+pub const ALLOW_N_SOURCE: &str = r#"-- this script has side-effects, so it requires replicate commands mode
 redis.replicate_commands()
 
 local rate_limit_key = KEYS[1]
@@ -13,24 +14,58 @@ local emission_interval = ARGV[1]
 local burst_offset = ARGV[2]
 local tat_increment = ARGV[3]
 local cost = ARGV[4]
+local time_epoch = tonumber(ARGV[5])
+local time_scale = tonumber(ARGV[6])
+local ttl_rounding = ARGV[7]
+local partial = ARGV[8] == "1"
 
 -- redis returns time as an array containing two integers: seconds of the epoch
 -- time (10 digits) and microseconds (6 digits). for convenience we need to
--- convert them to a floating point number. the resulting number is 16 digits,
--- bordering on the limits of a 64-bit double-precision floating point number.
--- adjust the epoch to be relative to Jan 1, 2017 00:00:00 GMT to avoid floating
--- point problems. this approach is good until "now" is 2,483,228,799 (Wed, 09
--- Sep 2048 01:46:39 GMT), when the adjusted value is 16 digits.
+-- convert them to a single number, in the epoch and unit given by the limiter.
+--
+-- by default the epoch is Jan 1, 2017 00:00:00 GMT and the unit is seconds,
+-- the resulting floating point number would be 16 digits relative to the unix
+-- epoch, bordering on the limits of a 64-bit double-precision floating point
+-- number. this approach is good until "now" is 2,483,228,799 (Wed, 09 Sep 2048
+-- 01:46:39 GMT), when the adjusted value is 16 digits.
+--
+-- alternatively the epoch is the unix epoch and the unit is microseconds,
+-- so all the times are integers, which a double represents exactly until the
+-- year 2255.
 local redis_now = redis.call("TIME")
-local jan_1_2017 = 1483228800
-local now = (redis_now[1] - jan_1_2017) + (redis_now[2] / 1000000)
+local now = (redis_now[1] - time_epoch) * time_scale + redis_now[2] * time_scale / 1000000
 
 local tat = redis.call("GET", rate_limit_key)
-if not tat then
+local first_seen = not tat
+local clamped = false
+if first_seen then
   tat = now
 else
+  -- an allowed request never moves the tat beyond now + burst_offset, so a
+  -- tat further in the future was stored before the clock moved backward.
+  -- clamp it, and store the clamp below even if limited, so the key is
+  -- limited for at most a full burst, not for as long as the clock jump.
   tat = tonumber(tat)
+  clamped = tat > now + burst_offset
+  if clamped then
+    tat = now + burst_offset
+  end
+end
+
+-- in partial mode, consume as many of the requests as currently available,
+-- or report when a single request can be made if none is: the increment of a
+-- single request then only computes retry_after, since the check is limited.
+local granted = tonumber(cost)
+if partial then
+  local available = math.floor((now - math.max(tat, now) + burst_offset) / emission_interval)
+  granted = math.max(math.min(granted, available), 0)
+  if granted > 0 or tonumber(cost) == 0 then
+    tat_increment = granted * emission_interval
+  else
+    tat_increment = emission_interval
+  end
 end
+
 local new_tat = math.max(tat, now) + tat_increment
 local allow_at = new_tat - burst_offset
 
@@ -41,18 +76,287 @@ local reset_after
 
 if allow_at > now then
   limited = true
-  remaining = math.floor((now - tat + burst_offset) / emission_interval)
+  granted = 0
+  remaining = math.floor((now - math.max(tat, now) + burst_offset) / emission_interval)
   retry_after = allow_at - now
-  reset_after = tat - now
+  reset_after = math.max(tat, now) - now
+  if clamped then
+    redis.call("SET", rate_limit_key, tat, "EX", math.max(math.ceil(reset_after / time_scale), 1))
+  end
 else
   limited = false
   remaining = math.floor((now - allow_at) / emission_interval)
   retry_after = -1
   reset_after = new_tat - now
-  redis.call("SET", rate_limit_key, new_tat, "EX", math.ceil(reset_after))
+  -- the key expires when the tat is reached, rounded to whole seconds as configured.
+  -- rounding down expires the key early, which grants a bit of extra burst.
+  local ttl = reset_after / time_scale
+  if ttl_rounding == "floor" then
+    ttl = math.floor(ttl)
+  elseif ttl_rounding == "round" then
+    ttl = math.floor(ttl + 0.5)
+  else
+    ttl = math.ceil(ttl)
+  end
+  -- checking 0 requests is a peek, which writes nothing.
+  if granted > 0 then
+    redis.call("SET", rate_limit_key, new_tat, "EX", math.max(ttl, 1))
+  end
+end
+
+-- redis truncates numbers to integers in the reply, so reply the durations
+-- in whole microseconds, which keeps their precision for any period.
+local function to_micros(duration)
+  return math.floor(duration * 1000000 / time_scale + 0.5)
+end
+if retry_after >= 0 then
+  retry_after = to_micros(retry_after)
+end
+
+return {limited, remaining, retry_after, to_micros(reset_after), first_seen, granted}
+"#;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) static ALLOW_N_SCRIPT: LazyLock<redis::Script> =
+    LazyLock::new(|| redis::Script::new(ALLOW_N_SOURCE));
+
+/// Lua source of the script run by `Limiter::refund_n`.
+pub const REFUND_N_SOURCE: &str = r#"-- this script has side-effects, so it requires replicate commands mode
+redis.replicate_commands()
+
+local rate_limit_key = KEYS[1]
+local tat_decrement = ARGV[1]
+local time_epoch = tonumber(ARGV[2])
+local time_scale = tonumber(ARGV[3])
+local ttl_rounding = ARGV[4]
+
+-- same time conversion as the allow script, see the comment there.
+local redis_now = redis.call("TIME")
+local now = (redis_now[1] - time_epoch) * time_scale + redis_now[2] * time_scale / 1000000
+
+local tat = redis.call("GET", rate_limit_key)
+if not tat then
+  return 0
+end
+
+-- never move the tat before now, a refund can't grant more than a full burst.
+local new_tat = math.max(tonumber(tat) - tat_decrement, now)
+local reset_after = new_tat - now
+if reset_after > 0 then
+  -- same ttl rounding as the allow script, see the comment there.
+  local ttl = reset_after / time_scale
+  if ttl_rounding == "floor" then
+    ttl = math.floor(ttl)
+  elseif ttl_rounding == "round" then
+    ttl = math.floor(ttl + 0.5)
+  else
+    ttl = math.ceil(ttl)
+  end
+  redis.call("SET", rate_limit_key, new_tat, "EX", math.max(ttl, 1))
+else
+  redis.call("DEL", rate_limit_key)
+end
+
+return 1
+"#;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) static REFUND_N_SCRIPT: LazyLock<redis::Script> =
+    LazyLock::new(|| redis::Script::new(REFUND_N_SOURCE));
+
+// Read-only version of the allow script, which never writes the tat.
+// Keep the math in sync with `ALLOW_N_SOURCE`.
+/// Lua source of the read-only GCRA script run by `Limiter::peek`.
+pub const PEEK_N_SOURCE: &str = r#"local rate_limit_key = KEYS[1]
+local emission_interval = ARGV[1]
+local burst_offset = ARGV[2]
+local tat_increment = ARGV[3]
+local time_epoch = tonumber(ARGV[4])
+local time_scale = tonumber(ARGV[5])
+
+-- same time conversion as the allow script, see the comment there.
+local redis_now = redis.call("TIME")
+local now = (redis_now[1] - time_epoch) * time_scale + redis_now[2] * time_scale / 1000000
+
+local tat = redis.call("GET", rate_limit_key)
+if not tat then
+  tat = now
+else
+  -- same clamp as the allow script, see the comment there.
+  tat = math.min(tonumber(tat), now + burst_offset)
 end
+local new_tat = math.max(tat, now) + tat_increment
+local allow_at = new_tat - burst_offset
+
+local limited
+local remaining
+local retry_after
+local reset_after
 
-return {limited, remaining, retry_after, reset_after}
-"#,
+if allow_at > now then
+  limited = true
+  remaining = math.floor((now - math.max(tat, now) + burst_offset) / emission_interval)
+  retry_after = allow_at - now
+  reset_after = math.max(tat, now) - now
+else
+  limited = false
+  remaining = math.floor((now - allow_at) / emission_interval)
+  retry_after = -1
+  reset_after = new_tat - now
+end
+
+-- same reply of the durations as the allow script, see the comment there.
+local function to_micros(duration)
+  return math.floor(duration * 1000000 / time_scale + 0.5)
+end
+if retry_after >= 0 then
+  retry_after = to_micros(retry_after)
+end
+
+return {limited, remaining, retry_after, to_micros(reset_after)}
+"#;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) static PEEK_N_SCRIPT: LazyLock<redis::Script> =
+    LazyLock::new(|| redis::Script::new(PEEK_N_SOURCE));
+
+// The counter key is derived from the Redis time, so it isn't declared in KEYS
+// and the script is not suitable for Redis Cluster.
+/// Lua source of the script run by `Limiter::allow_n` with `Algorithm::FixedWindow`.
+pub const FIXED_WINDOW_SOURCE: &str = r#"-- this script has side-effects, so it requires replicate commands mode
+redis.replicate_commands()
+
+local rate_limit_key = KEYS[1]
+local period_seconds = tonumber(ARGV[1])
+local max_count = tonumber(ARGV[2])
+local cost = tonumber(ARGV[3])
+local retention_seconds = tonumber(ARGV[4])
+local partial = ARGV[5] == "1"
+-- only read the counter, e.g. for `peek` and dry runs.
+local peek = ARGV[6] == "1"
+
+local redis_now = redis.call("TIME")
+local now_seconds = tonumber(redis_now[1])
+local window_start = now_seconds - (now_seconds % period_seconds)
+local window_key = rate_limit_key .. ":" .. window_start
+local reset_after = window_start + period_seconds - (now_seconds + redis_now[2] / 1000000)
+
+local count = tonumber(redis.call("GET", window_key) or "0")
+local first_seen = count == 0
+if partial then
+  -- consume as many of the requests as left in the window, or check a single
+  -- one if none is left, so the check is limited.
+  cost = math.min(cost, math.max(max_count - count, 1))
+end
+
+local limited
+local remaining
+local retry_after
+
+if count + cost > max_count then
+  limited = true
+  remaining = math.max(max_count - count, 0)
+  retry_after = reset_after
+else
+  limited = false
+  -- checking 0 requests is a peek, which writes nothing.
+  if peek or cost == 0 then
+    count = count + cost
+  else
+    count = redis.call("INCRBY", window_key, cost)
+    -- keep the counter after the window ends, so it can be audited.
+    redis.call("EXPIRE", window_key, math.ceil(reset_after + retention_seconds))
+  end
+  remaining = max_count - count
+  retry_after = -1
+end
+
+local granted = 0
+if not limited then
+  granted = cost
+end
+
+-- same reply of the durations as the allow script, see the comment there.
+local function to_micros(duration)
+  return math.floor(duration * 1000000 + 0.5)
+end
+if retry_after >= 0 then
+  retry_after = to_micros(retry_after)
+end
+
+return {limited, remaining, retry_after, to_micros(reset_after), first_seen, granted}
+"#;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) static FIXED_WINDOW_SCRIPT: LazyLock<redis::Script> =
+    LazyLock::new(|| redis::Script::new(FIXED_WINDOW_SOURCE));
+
+/// Lua source of the script run by `Limiter::refund_n` with `Algorithm::FixedWindow`.
+/// Only the requests counted in the current window can be given back.
+pub const FIXED_WINDOW_REFUND_SOURCE: &str = r#"-- this script has side-effects, so it requires replicate commands mode
+redis.replicate_commands()
+
+local rate_limit_key = KEYS[1]
+local period_seconds = tonumber(ARGV[1])
+local cost = tonumber(ARGV[2])
+
+local redis_now = redis.call("TIME")
+local now_seconds = tonumber(redis_now[1])
+local window_start = now_seconds - (now_seconds % period_seconds)
+local window_key = rate_limit_key .. ":" .. window_start
+
+local count = tonumber(redis.call("GET", window_key) or "0")
+local refunded = math.min(cost, count)
+if refunded > 0 then
+  -- DECRBY keeps the expiry of the counter.
+  redis.call("DECRBY", window_key, refunded)
+end
+
+return refunded
+"#;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) static FIXED_WINDOW_REFUND_SCRIPT: LazyLock<redis::Script> =
+    LazyLock::new(|| redis::Script::new(FIXED_WINDOW_REFUND_SOURCE));
+
+#[cfg(feature = "functions")]
+pub(crate) const FUNCTION_LIBRARY_NAME: &str = "redis_rate";
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) const ALLOW_N_FUNCTION: &str = "redis_rate_allow_n";
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) const REFUND_N_FUNCTION: &str = "redis_rate_refund_n";
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) const PEEK_N_FUNCTION: &str = "redis_rate_peek_n";
+
+/// The GCRA scripts as a Redis Function library, for `FUNCTION LOAD`.
+/// The bodies are the same as the scripts, so the results are identical.
+#[cfg(feature = "functions")]
+pub(crate) static FUNCTION_LIBRARY: LazyLock<String> = LazyLock::new(|| {
+    // Functions always replicate their effects, and can't call `redis.replicate_commands`.
+    let body = |source: &str| {
+        source.replace(
+            "-- this script has side-effects, so it requires replicate commands mode\nredis.replicate_commands()\n",
+            "",
+        )
+    };
+    format!(
+        "#!lua name={FUNCTION_LIBRARY_NAME}\n\
+         redis.register_function('{ALLOW_N_FUNCTION}', function(KEYS, ARGV)\n{}end)\n\
+         redis.register_function('{REFUND_N_FUNCTION}', function(KEYS, ARGV)\n{}end)\n\
+         redis.register_function{{function_name='{PEEK_N_FUNCTION}', callback=function(KEYS, ARGV)\n{}end, flags={{'no-writes'}}}}\n",
+        body(ALLOW_N_SOURCE),
+        body(REFUND_N_SOURCE),
+        body(PEEK_N_SOURCE),
     )
 });
+
+#[cfg(feature = "functions")]
+#[test]
+fn test_function_library() {
+    assert!(FUNCTION_LIBRARY.starts_with("#!lua name=redis_rate\n"));
+    assert!(!FUNCTION_LIBRARY.contains("replicate_commands"));
+    assert_eq!(
+        FUNCTION_LIBRARY.matches("redis.register_function").count(),
+        3
+    );
+}
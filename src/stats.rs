@@ -0,0 +1,63 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters of the limit checks of a limiter and its clones, see `Limiter::stats_snapshot`.
+///
+/// Log them on a timer to see whether the `local_accelerate` feature is paying off,
+/// i.e. how many checks are answered by the local cache instead of Redis.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LimiterStats {
+    /// Checks limited by the local cache, without calling Redis.
+    /// Always 0 without the `local_accelerate` feature.
+    pub local_hits: u64,
+    /// Checks which called Redis.
+    pub redis_calls: u64,
+    /// Checks which allowed the requests.
+    pub allowed: u64,
+    /// Checks which limited the requests, including the local hits.
+    pub denied: u64,
+    /// Checks which failed, before the failure mode is applied.
+    pub errors: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct StatsCounters {
+    pub(crate) local_hits: AtomicU64,
+    pub(crate) redis_calls: AtomicU64,
+    pub(crate) allowed: AtomicU64,
+    pub(crate) denied: AtomicU64,
+    pub(crate) errors: AtomicU64,
+}
+
+impl StatsCounters {
+    pub(crate) fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a check by whether it is limited, or failed.
+    pub(crate) fn record<E>(&self, limited: Result<bool, &E>) {
+        match limited {
+            Ok(false) => Self::increment(&self.allowed),
+            Ok(true) => Self::increment(&self.denied),
+            Err(_) => Self::increment(&self.errors),
+        }
+    }
+
+    /// Read the counters, and set them to 0 if `reset`.
+    /// The counters are read one by one, so a snapshot taken during checks may be slightly skewed.
+    pub(crate) fn snapshot(&self, reset: bool) -> LimiterStats {
+        let read = |counter: &AtomicU64| {
+            if reset {
+                counter.swap(0, Ordering::Relaxed)
+            } else {
+                counter.load(Ordering::Relaxed)
+            }
+        };
+        LimiterStats {
+            local_hits: read(&self.local_hits),
+            redis_calls: read(&self.redis_calls),
+            allowed: read(&self.allowed),
+            denied: read(&self.denied),
+            errors: read(&self.errors),
+        }
+    }
+}